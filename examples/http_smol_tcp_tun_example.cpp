@@ -43,7 +43,7 @@ int main()
         {0xfe80, 0, 0, 0, 0, 0, 0, 0x100}});
 
     SmolSocket smolSocket = tunSmolStack.addSocket(SOCKET_TCP);
-    uint8_t result = tunSmolStack.finalize();
+    SmolError result = tunSmolStack.finalize();
     CIpEndpoint endpointNone{
         CIpEndpointType::None,
         CIpv4Address{},
@@ -57,7 +57,7 @@ int main()
     };
     State state = State::Connect;
 
-    if (result == 0)
+    if (result == SmolError::Ok)
     {
         //socketLoop(tunSmolStack, handle);
         while (true)
@@ -103,7 +103,7 @@ int main()
     else
     {
         //throw
-        std::cout << "error on finalize" << std::endl;
+        std::cout << "error on finalize: " << TunSmolStack::lastErrorMessage() << std::endl;
     }
 
     getchar();
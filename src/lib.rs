@@ -1,6 +1,6 @@
 
 pub mod virtual_tun;
-pub use virtual_tun::VirtualTunInterface;
+pub use virtual_tun::{VirtualTapInterface, VirtualTunInterface};
 #[cfg(test)]
 mod tests {
     #[test]
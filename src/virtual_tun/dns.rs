@@ -0,0 +1,288 @@
+use super::error::{CSmolError, SmolError, SmolResult};
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
+
+/*
+    Just enough of DNS (RFC 1035) for a stub resolver: build A/AAAA
+    queries with recursion desired, and pick the question and the
+    addresses out of responses. Everything else in a response is skipped
+*/
+
+pub const DNS_PORT: u16 = 53;
+//Each attempt sends the queries again, to the next server
+pub const MAX_ATTEMPTS: usize = 3;
+pub const RETRY_INTERVAL_MILLIS: u64 = 1000;
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+//The two top bits of a label length mark a compression pointer
+const LABEL_POINTER: u8 = 0xc0;
+
+pub struct DnsResponse {
+    pub id: u16,
+    //Name (lowercase, without the final dot) and type of the first question
+    pub name: String,
+    pub query_type: u16,
+    //0 means no error, 3 means the name doesn't exist
+    pub rcode: u8,
+    pub addresses: Vec<IpAddress>,
+}
+
+pub fn build_query(id: u16, name: &str, query_type: u16) -> SmolResult<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(SmolError::new(
+            CSmolError::InvalidArgument,
+            format!("'{}' is not a valid DNS name", name),
+        ));
+    }
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    //One question, no answer, authority or additional records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                format!("'{}' is not a valid DNS name", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&query_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+//None if data isn't a well formed response
+pub fn parse_response(data: &[u8]) -> Option<DnsResponse> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    let id = read_u16(data, 0)?;
+    let flags = read_u16(data, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let question_count = read_u16(data, 4)?;
+    let answer_count = read_u16(data, 6)?;
+    //Our queries have one question, which the response echoes
+    if question_count == 0 {
+        return None;
+    }
+    let (name, mut position) = read_name(data, HEADER_LEN)?;
+    let query_type = read_u16(data, position)?;
+    //Type and class
+    position += 4;
+    for _ in 1..question_count {
+        position = skip_name(data, position)? + 4;
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answer_count {
+        position = skip_name(data, position)?;
+        let record_type = read_u16(data, position)?;
+        let class = read_u16(data, position + 2)?;
+        //Type, class and TTL come before the data length
+        let data_len = read_u16(data, position + 8)? as usize;
+        position += 10;
+        let record_data = data.get(position..position + data_len)?;
+        position += data_len;
+        if class != CLASS_IN {
+            continue;
+        }
+        //CNAMEs are skipped, recursive servers send the records they point to as well
+        match (record_type, data_len) {
+            (TYPE_A, 4) => addresses.push(IpAddress::Ipv4(Ipv4Address::from_bytes(record_data))),
+            (TYPE_AAAA, 16) => addresses.push(IpAddress::Ipv6(Ipv6Address::from_bytes(record_data))),
+            _ => {}
+        }
+    }
+    Some(DnsResponse {
+        id: id,
+        name: name,
+        query_type: query_type,
+        rcode: (flags & 0x000f) as u8,
+        addresses: addresses,
+    })
+}
+
+fn read_u16(data: &[u8], position: usize) -> Option<u16> {
+    let bytes = data.get(position..position + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/*
+    Reads the name at position, following compression pointers.
+    Returns it in lowercase and the position right after it
+*/
+fn read_name(data: &[u8], mut position: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    //Pointers must go backwards, so a loop of them can't go on forever
+    let mut limit = position;
+    loop {
+        let len = *data.get(position)?;
+        if len & LABEL_POINTER == LABEL_POINTER {
+            let target = (((len & !LABEL_POINTER) as usize) << 8) | *data.get(position + 1)? as usize;
+            if target >= limit {
+                return None;
+            }
+            if end.is_none() {
+                end = Some(position + 2);
+            }
+            limit = target;
+            position = target;
+            continue;
+        }
+        position += 1;
+        if len == 0 {
+            break;
+        }
+        let label = data.get(position..position + len as usize)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        position += len as usize;
+    }
+    Some((labels.join("."), end.unwrap_or(position)))
+}
+
+//Returns the position right after the name that starts at position
+fn skip_name(data: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(position)?;
+        if len & LABEL_POINTER == LABEL_POINTER {
+            //A pointer ends the name, and takes 2 bytes
+            return Some(position + 2);
+        }
+        position += 1;
+        if len == 0 {
+            return Some(position);
+        }
+        position += len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_CNAME: u16 = 5;
+
+    //A response to query with the given (type, data) answers, all for the question's name
+    fn response(query: &[u8], answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut data = query.to_vec();
+        data[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        data[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for &(record_type, record_data) in answers {
+            //Pointer to the question's name, right after the header
+            data.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            data.extend_from_slice(&record_type.to_be_bytes());
+            data.extend_from_slice(&CLASS_IN.to_be_bytes());
+            data.extend_from_slice(&[0, 0, 0, 60]);
+            data.extend_from_slice(&(record_data.len() as u16).to_be_bytes());
+            data.extend_from_slice(record_data);
+        }
+        data
+    }
+
+    #[test]
+    fn builds_query() {
+        let query = build_query(0xbeef, "Example.com.", TYPE_A).ok().unwrap();
+        let mut expected = vec![0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x07Example\x03com\x00");
+        expected.extend_from_slice(&[0, 1, 0, 1]);
+        assert_eq!(query, expected);
+    }
+
+    #[test]
+    fn a_round_trip() {
+        let query = build_query(1, "example.com", TYPE_A).ok().unwrap();
+        let response = parse_response(&response(&query, &[(TYPE_A, &[93, 184, 216, 34])])).unwrap();
+        assert_eq!(response.id, 1);
+        assert_eq!(response.rcode, 0);
+        assert_eq!(response.name, "example.com");
+        assert_eq!(response.query_type, TYPE_A);
+        assert_eq!(response.addresses, vec![IpAddress::v4(93, 184, 216, 34)]);
+    }
+
+    #[test]
+    fn aaaa_round_trip() {
+        let query = build_query(2, "EXAMPLE.com", TYPE_AAAA).ok().unwrap();
+        let address: [u8; 16] = [0x26, 0x06, 0x28, 0, 0x02, 0x20, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        let response = parse_response(&response(&query, &[(TYPE_AAAA, &address)])).unwrap();
+        assert_eq!(response.name, "example.com");
+        assert_eq!(response.query_type, TYPE_AAAA);
+        assert_eq!(
+            response.addresses,
+            vec![IpAddress::v6(0x2606, 0x2800, 0x220, 1, 0, 0, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn skips_cname() {
+        let query = build_query(3, "www.example.com", TYPE_A).ok().unwrap();
+        let target = b"\x07example\x03com\x00";
+        let data = response(&query, &[(TYPE_CNAME, target), (TYPE_A, &[10, 0, 0, 1])]);
+        let response = parse_response(&data).unwrap();
+        assert_eq!(response.addresses, vec![IpAddress::v4(10, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn keeps_rcode() {
+        let query = build_query(4, "nowhere.example", TYPE_A).ok().unwrap();
+        let mut data = response(&query, &[]);
+        data[3] |= 3;
+        let response = parse_response(&data).unwrap();
+        assert_eq!(response.rcode, 3);
+        assert!(response.addresses.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_answers() {
+        let query = build_query(5, "example.com", TYPE_A).ok().unwrap();
+        let data = response(&query, &[(TYPE_A, &[93, 184, 216, 34])]);
+        //Every cut inside the answer, including one in its data
+        for len in query.len()..data.len() {
+            assert!(parse_response(&data[..len]).is_none(), "accepted {} bytes", len);
+        }
+        assert!(parse_response(&data[..HEADER_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn rejects_data_length_past_the_end() {
+        let query = build_query(6, "example.com", TYPE_A).ok().unwrap();
+        let mut data = response(&query, &[(TYPE_A, &[93, 184, 216, 34])]);
+        let data_len_position = data.len() - 6;
+        data[data_len_position..data_len_position + 2].copy_from_slice(&100u16.to_be_bytes());
+        assert!(parse_response(&data).is_none());
+    }
+
+    #[test]
+    fn rejects_queries() {
+        let query = build_query(7, "example.com", TYPE_A).ok().unwrap();
+        assert!(parse_response(&query).is_none());
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let mut data = vec![0, 8, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        //The question's name points at itself
+        data.extend_from_slice(&[0xc0, HEADER_LEN as u8, 0, 1, 0, 1]);
+        assert!(parse_response(&data).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert!(build_query(1, "", TYPE_A).is_err());
+        assert!(build_query(1, ".", TYPE_A).is_err());
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+        assert!(build_query(1, &"a".repeat(64), TYPE_A).is_err());
+        assert!(build_query(1, &"a".repeat(63), TYPE_A).is_ok());
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        assert!(build_query(1, &long_name, TYPE_A).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/*
+    Error codes returned by every exported function.
+    Warning: keep this synced with SmolError on interface.h
+*/
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CSmolError {
+    Ok = 0,
+    //Nothing to receive or accept right now, try again later
+    NoData = 1,
+    SocketNotFound = 2,
+    //The operation doesn't apply to this socket type (e.g. tcp_connect on UDP)
+    WrongSocketType = 3,
+    InvalidArgument = 4,
+    //Called before smol_stack_finalize
+    NotFinalized = 5,
+    //Called after smol_stack_finalize
+    AlreadyFinalized = 6,
+    //The operation doesn't apply to this stack type (e.g. VirtualTun only)
+    NotSupported = 7,
+    //The socket was closed, nothing can be sent on it anymore
+    SocketClosed = 8,
+    //smoltcp refused the operation, the message has the details
+    Smoltcp = 9,
+    //The TUN/TAP device couldn't be opened or waited on
+    Device = 10,
+    //Rust panicked. The stack may be in a bad state and shouldn't be used anymore
+    Panic = 11,
+    //A bounded queue was full and its drop policy is to return an error
+    QueueFull = 12,
+    //The caller's buffer can't hold the packet, the size needed was written back
+    BufferTooSmall = 13,
+    //Nothing came back in time (a DNS query, for example)
+    Timeout = 14,
+    //The peer closed its side of the connection and everything it sent was received
+    EndOfStream = 15,
+    //The peer reset the connection, or it timed out
+    ConnectionReset = 16,
+}
+
+pub struct SmolError {
+    pub code: CSmolError,
+    pub message: String,
+}
+
+pub type SmolResult<T> = Result<T, SmolError>;
+
+impl SmolError {
+    pub fn new<S: Into<String>>(code: CSmolError, message: S) -> SmolError {
+        SmolError {
+            code: code,
+            message: message.into(),
+        }
+    }
+
+    pub fn virtual_tun_only() -> SmolError {
+        SmolError::new(
+            CSmolError::NotSupported,
+            "this operation is only supported on VirtualTun and VirtualTap stacks",
+        )
+    }
+
+    pub fn socket_not_found(smol_socket_handle: usize) -> SmolError {
+        SmolError::new(
+            CSmolError::SocketNotFound,
+            format!("no socket with handle {}", smol_socket_handle),
+        )
+    }
+}
+
+impl From<smoltcp::Error> for SmolError {
+    fn from(e: smoltcp::Error) -> SmolError {
+        SmolError::new(CSmolError::Smoltcp, format!("{}", e))
+    }
+}
+
+thread_local! {
+    //Message of the last error returned to C++ on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    //Interior NULs would make CString fail, so they're dropped
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/*
+    Returns the message of the last error on this thread, or null if there
+    wasn't any. The pointer is valid until the next exported call on the
+    same thread, so C++ should copy it
+*/
+pub fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| match *last_error.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("panic: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("panic: {}", message)
+    } else {
+        "panic".to_owned()
+    }
+}
+
+/*
+    Runs f, turning its error or a panic into a CSmolError, so nothing
+    unwinds into C++. Every exported function goes through here
+*/
+pub fn ffi_guard<F>(f: F) -> CSmolError
+where
+    F: FnOnce() -> SmolResult<()>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => CSmolError::Ok,
+        Ok(Err(e)) => {
+            let code = e.code;
+            set_last_error(e.message);
+            code
+        }
+        Err(payload) => {
+            set_last_error(panic_message(payload));
+            CSmolError::Panic
+        }
+    }
+}
+
+/*
+    Writes value to an out parameter coming from C++,
+    failing instead of writing through a null pointer
+*/
+pub fn write_out<T>(out: *mut T, value: T) -> SmolResult<()> {
+    if out.is_null() {
+        return Err(SmolError::new(
+            CSmolError::InvalidArgument,
+            "null output pointer",
+        ));
+    }
+    unsafe { ptr::write(out, value) };
+    Ok(())
+}
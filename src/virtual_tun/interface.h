@@ -5,10 +5,12 @@
 #include <limits>
 #include <queue>
 #include <chrono>
-#include <iostream>
 #include <memory>
 #include <optional>
 #include <utility>
+#include <stdexcept>
+#include <string>
+#include <vector>
 #include "utils.h"
 
 typedef void *SmolStackPtr;
@@ -16,6 +18,7 @@ typedef size_t SocketHandle;
 
 static const int SOCKET_TCP = 0;
 static const int SOCKET_UDP = 1;
+static const int SOCKET_ICMP = 2;
 
 namespace smoltcp
 {
@@ -30,7 +33,7 @@ namespace smoltcp
 
     struct NoDeleter
     {
-        void operator()(uint8_t *b) { std::cout << "not going to delete Buffer" << std::endl; }
+        void operator()(uint8_t *b) {}
     };
 
     //BIG TODO: does this delete everything or just the pointed value?
@@ -87,19 +90,107 @@ namespace smoltcp
         CIpv6Address ipv6Address;
     };
 
+    struct CEthernetAddress
+    {
+        uint8_t address[6];
+    };
+
     struct CIpv4Cidr
     {
         CIpv4Address address;
-        uint32_t prefix;
+        uint8_t prefix;
     };
 
     struct CIpv6Cidr
     {
         CIpv6Address address;
-        uint64_t prefix;
+        uint8_t prefix;
+    };
+
+    //Warning: keep this synced with CDhcpLease on interface.rs
+    struct CDhcpLease
+    {
+        uint8_t hasAddress;
+        CIpv4Cidr address;
+        uint8_t hasRouter;
+        CIpv4Address router;
+        uint8_t dnsServersLen;
+        CIpv4Address dnsServers[3];
+    };
+
+    //Either family, like CIpAddress
+    struct CIpCidr
+    {
+        uint8_t isIpv4 = 0;
+        CIpv4Cidr ipv4Cidr;
+        CIpv6Cidr ipv6Cidr;
+    };
+
+    //Warning: keep synced with CHECKSUM_* on interface.rs
+    enum CChecksum : uint8_t
+    {
+        ChecksumBoth = 0,
+        ChecksumTx = 1,
+        ChecksumRx = 2,
+        ChecksumNone = 3
+    };
+
+    //Warning: keep synced with DROP_POLICY_* on interface.rs
+    enum CDropPolicy : uint8_t
+    {
+        //Packets arriving at a full queue are dropped and counted
+        DropPolicyTailDrop = 0,
+        //virtualTunSend returns SmolError::QueueFull, smoltcp retries later
+        DropPolicyError = 1
+    };
+
+    /*
+        mtu 0 means 1500, maxBurstSize 0 means no limit. checksum
+        applies to every protocol, Tx means smoltcp only computes
+        checksums on sent packets, Rx only verifies received ones.
+        Queue capacities are in packets, 0 means unbounded. The inside
+        queue holds what virtualTunReceive* returns, the outside one
+        what virtualTunSend sends
+    */
+    struct CVirtualTunOptions
+    {
+        size_t mtu = 0;
+        size_t maxBurstSize = 0;
+        CChecksum checksum = ChecksumBoth;
+        size_t insideQueueCapacity = 0;
+        size_t outsideQueueCapacity = 0;
+        CDropPolicy dropPolicy = DropPolicyTailDrop;
     };
 
-    enum CIpEndpointType
+    struct CDropCounters
+    {
+        uint64_t fromInside;
+        uint64_t fromOutside;
+    };
+
+    /*
+        Buffer sizes for a new socket, 0 means default.
+        Packet slots are how many datagrams a UDP, ICMP or raw buffer holds
+    */
+    struct CSocketOptions
+    {
+        size_t rxBufferSize = 0;
+        size_t txBufferSize = 0;
+        size_t rxPacketSlots = 0;
+        size_t txPacketSlots = 0;
+    };
+
+    //Warning: keep this synced with CIcmpEchoReply on interface.rs
+    struct CIcmpEchoReply
+    {
+        CIpAddress source;
+        uint16_t seqNo;
+        uint64_t rttMillis;
+        size_t payloadLen;
+    };
+
+    //Warning: keep the underlying type synced with CIpEndpoint.endpoint_type on interface.rs
+    enum CIpEndpointType : uint8_t
     {
         None = 0,
         Ipv4 = 1,
@@ -114,36 +205,158 @@ namespace smoltcp
         uint16_t port;
     };
 
+    //Warning: keep this synced with CSmolError on error.rs
+    enum class SmolError : int
+    {
+        Ok = 0,
+        NoData = 1,
+        SocketNotFound = 2,
+        WrongSocketType = 3,
+        InvalidArgument = 4,
+        NotFinalized = 5,
+        AlreadyFinalized = 6,
+        NotSupported = 7,
+        SocketClosed = 8,
+        Smoltcp = 9,
+        Device = 10,
+        Panic = 11,
+        QueueFull = 12,
+        BufferTooSmall = 13,
+        Timeout = 14,
+        EndOfStream = 15,
+        ConnectionReset = 16
+    };
+
+    //Warning: keep this synced with CTcpState on interface.rs
+    enum class CTcpState : uint8_t
+    {
+        Closed = 0,
+        Listen = 1,
+        SynSent = 2,
+        SynReceived = 3,
+        Established = 4,
+        FinWait1 = 5,
+        FinWait2 = 6,
+        CloseWait = 7,
+        Closing = 8,
+        LastAck = 9,
+        TimeWait = 10
+    };
+
+    /*
+        Value for tcpSetOption: KeepAlive and Timeout take milliseconds (0
+        disables them), Nagle takes 0 or 1 (only 0 is supported), HopLimit
+        takes 1 to 255 (0 goes back to the default).
+        Warning: keep this synced with smol_stack_tcp_set_option on interface.rs
+    */
+    enum class TcpOption : uint8_t
+    {
+        KeepAlive = 0,
+        Timeout = 1,
+        Nagle = 2,
+        HopLimit = 3
+    };
+
+    /*
+        Queued bytes are in smoltcp's buffers, toSendLen and receivedLen
+        count packets waiting in the socket's queues. rttMillis is the
+        smoothed round trip time of the data sent, 0 until some of it
+        was acknowledged.
+        Warning: keep this synced with CTcpInfo on interface.rs
+    */
+    struct CTcpInfo
+    {
+        CTcpState state;
+        CIpEndpoint localEndpoint;
+        CIpEndpoint remoteEndpoint;
+        size_t txQueuedBytes;
+        size_t rxQueuedBytes;
+        size_t toSendLen;
+        size_t receivedLen;
+        uint64_t rttMillis;
+    };
+
+    /*
+        Run from spin when the socket changes, any of them may be null.
+        onConnected and onClosed are TCP only. onError gets ConnectionReset
+        when a TCP connection is reset or times out, or the error spin
+        returned. They run after spin unlocked the stack, so they may call
+        this stack.
+        Warning: keep this synced with CSocketCallbacks on interface.rs
+    */
+    struct CSocketCallbacks
+    {
+        void (*onConnected)(size_t socketHandle, void *context) = nullptr;
+        void (*onReadable)(size_t socketHandle, void *context) = nullptr;
+        void (*onWritable)(size_t socketHandle, void *context) = nullptr;
+        void (*onClosed)(size_t socketHandle, void *context) = nullptr;
+        void (*onError)(size_t socketHandle, SmolError error, void *context) = nullptr;
+        void *context = nullptr;
+    };
+
     extern "C" void cppDeleteArray(uint8_t *data);
     extern "C" void cppDeletePointer(uint8_t *data);
     extern "C" uint8_t *cpp_allocate_buffer(size_t size);
     extern "C" uint8_t *cpp_allocate_buffer_zero_terminated(size_t size);
 
-    extern "C" SmolStackPtr smol_stack_smol_stack_new_virtual_tun(const char *interfaceName);
-    extern "C" SmolStackPtr smol_stack_smol_stack_new_tun(const char *interfaceName);
-    extern "C" SmolStackPtr smol_stack_smol_stack_new_tap(const char *interfaceName);
-    extern "C" uint8_t smol_stack_add_socket(SmolStackPtr, uint8_t socketType, SocketHandle socketHandle);
-    extern "C" void smol_stack_poll(SmolStackPtr);
-    extern "C" void smol_stack_phy_wait(SmolStackPtr, int64_t timestamp);
-    extern "C" void smol_stack_spin(SmolStackPtr, SocketHandle socketHandle);
-    extern "C" void smol_stack_spin_all(SmolStackPtr);
-    extern "C" uint8_t smol_stack_tcp_connect(SmolStackPtr, SocketHandle socketHandle, CIpAddress, uint16_t src_port, uint16_t dst_port);
-    extern "C" uint8_t smol_stack_tcp_connect_ipv4(SmolStackPtr, SocketHandle socketHandle, CIpv4Address, uint16_t src_port, uint16_t dst_port);
-    extern "C" uint8_t smol_stack_tcp_connect_ipv6(SmolStackPtr, SocketHandle socketHandle, CIpv6Address, uint16_t src_port, uint16_t dst_port);
-    extern "C" uint8_t smol_stack_smol_socket_send(SmolStackPtr, SocketHandle socketHandle, const uint8_t *data, size_t len, CIpEndpoint endpoint, void *, uint8_t (*)(void *));
-    extern "C" uint8_t smol_stack_smol_socket_send_copy(SmolStackPtr, SocketHandle socketHandle, const uint8_t *data, size_t len, CIpEndpoint endpoint);
-    extern "C" uint8_t smol_stack_smol_socket_receive(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t));
-    extern "C" uint8_t smol_stack_smol_socket_receive_wait(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t), CIpAddress *address);
-    extern "C" uint8_t smol_stack_smol_socket_may_send(SmolStackPtr, SocketHandle socketHandle);
-    extern "C" void smol_stack_add_ipv4_address(SmolStackPtr, CIpv4Cidr);
-    extern "C" void smol_stack_add_ipv6_address(SmolStackPtr, CIpv6Cidr);
-    extern "C" void smol_stack_add_default_v4_gateway(SmolStackPtr, CIpv4Address);
-    extern "C" void smol_stack_add_default_v6_gateway(SmolStackPtr, CIpv6Address);
-    extern "C" uint8_t smol_stack_finalize(SmolStackPtr);
-    extern "C" uint8_t smol_stack_virtual_tun_send(SmolStackPtr, const uint8_t *data, size_t len);
-    extern "C" uint8_t smol_stack_virtual_tun_receive_wait(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
-    extern "C" uint8_t smol_stack_virtual_tun_receive_instantly(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
-    extern "C" void smol_stack_destroy(void *);
+    extern "C" const char *smol_stack_last_error_message();
+    extern "C" SmolError smol_stack_smol_stack_new_virtual_tun(const char *interfaceName, const CVirtualTunOptions *options, SmolStackPtr *smolStack);
+    extern "C" SmolError smol_stack_smol_stack_new_virtual_tap(const char *interfaceName, const CVirtualTunOptions *options, CEthernetAddress ethernetAddress, SmolStackPtr *smolStack);
+    extern "C" SmolError smol_stack_smol_stack_new_tun(const char *interfaceName, SmolStackPtr *smolStack);
+    extern "C" SmolError smol_stack_smol_stack_new_tap(const char *interfaceName, SmolStackPtr *smolStack);
+    extern "C" SmolError smol_stack_new_socket_handle_key(SmolStackPtr, SocketHandle *socketHandle);
+    extern "C" SmolError smol_stack_add_socket(SmolStackPtr, uint8_t socketType, SocketHandle socketHandle);
+    extern "C" SmolError smol_stack_add_socket_with_options(SmolStackPtr, uint8_t socketType, SocketHandle socketHandle, const CSocketOptions *options);
+    extern "C" SmolError smol_stack_set_socket_callbacks(SmolStackPtr, SocketHandle socketHandle, const CSocketCallbacks *callbacks);
+    extern "C" SmolError smol_stack_add_raw_socket(SmolStackPtr, uint8_t ipVersion, uint8_t protocol, SocketHandle socketHandle, const CSocketOptions *options);
+    extern "C" SmolError smol_stack_socket_close(SmolStackPtr, SocketHandle socketHandle);
+    extern "C" SmolError smol_stack_tcp_shutdown_write(SmolStackPtr, SocketHandle socketHandle);
+    extern "C" SmolError smol_stack_socket_abort(SmolStackPtr, SocketHandle socketHandle);
+    extern "C" SmolError smol_stack_socket_remove(SmolStackPtr, SocketHandle socketHandle);
+    extern "C" SmolError smol_stack_poll(SmolStackPtr);
+    extern "C" SmolError smol_stack_phy_wait(SmolStackPtr, int64_t timestamp, uint64_t *waitedMillis);
+    extern "C" SmolError smol_stack_spin(SmolStackPtr, SocketHandle socketHandle);
+    extern "C" SmolError smol_stack_spin_all(SmolStackPtr);
+    extern "C" SmolError smol_stack_start(SmolStackPtr);
+    extern "C" SmolError smol_stack_stop(SmolStackPtr);
+    extern "C" SmolError smol_stack_tcp_connect(SmolStackPtr, SocketHandle socketHandle, CIpAddress, uint16_t src_port, uint16_t dst_port);
+    extern "C" SmolError smol_stack_tcp_listen(SmolStackPtr, SocketHandle socketHandle, uint16_t port);
+    extern "C" SmolError smol_stack_tcp_accept(SmolStackPtr, SocketHandle socketHandle, SocketHandle *acceptedSocketHandle);
+    extern "C" SmolError smol_stack_udp_bind(SmolStackPtr, SocketHandle socketHandle, uint16_t port);
+    extern "C" SmolError smol_stack_icmp_bind(SmolStackPtr, SocketHandle socketHandle, uint16_t ident);
+    extern "C" SmolError smol_stack_icmp_send_echo(SmolStackPtr, SocketHandle socketHandle, CIpAddress address, uint16_t seqNo, const uint8_t *data, size_t len);
+    extern "C" SmolError smol_stack_icmp_receive_echo(SmolStackPtr, SocketHandle socketHandle, CIcmpEchoReply *reply);
+    extern "C" SmolError smol_stack_tcp_connect_ipv4(SmolStackPtr, SocketHandle socketHandle, CIpv4Address, uint16_t src_port, uint16_t dst_port);
+    extern "C" SmolError smol_stack_tcp_connect_ipv6(SmolStackPtr, SocketHandle socketHandle, CIpv6Address, uint16_t src_port, uint16_t dst_port);
+    extern "C" SmolError smol_stack_smol_socket_send(SmolStackPtr, SocketHandle socketHandle, const uint8_t *data, size_t len, CIpEndpoint endpoint, void *, uint8_t (*)(void *));
+    extern "C" SmolError smol_stack_smol_socket_send_copy(SmolStackPtr, SocketHandle socketHandle, const uint8_t *data, size_t len, CIpEndpoint endpoint);
+    extern "C" SmolError smol_stack_smol_socket_receive(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t), CIpEndpoint *endpoint);
+    extern "C" SmolError smol_stack_smol_socket_receive_wait(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t), CIpEndpoint *endpoint);
+    extern "C" SmolError smol_stack_smol_socket_receive_wait_timeout(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t), CIpEndpoint *endpoint, uint64_t timeoutMillis);
+    extern "C" SmolError smol_stack_smol_socket_receive_into(SmolStackPtr, SocketHandle socketHandle, uint8_t *buffer, size_t len, size_t *written, CIpEndpoint *endpoint);
+    extern "C" SmolError smol_stack_tcp_recv_slice(SmolStackPtr, SocketHandle socketHandle, uint8_t *buffer, size_t len, size_t *written);
+    extern "C" SmolError smol_stack_tcp_set_option(SmolStackPtr, SocketHandle socketHandle, TcpOption option, uint64_t value);
+    extern "C" SmolError smol_stack_tcp_get_info(SmolStackPtr, SocketHandle socketHandle, CTcpInfo *info);
+    extern "C" SmolError smol_stack_smol_socket_may_send(SmolStackPtr, SocketHandle socketHandle, uint8_t *maySend);
+    extern "C" SmolError smol_stack_add_ipv4_address(SmolStackPtr, CIpv4Cidr);
+    extern "C" SmolError smol_stack_add_ipv6_address(SmolStackPtr, CIpv6Cidr);
+    extern "C" SmolError smol_stack_set_ethernet_address(SmolStackPtr, CEthernetAddress);
+    extern "C" SmolError smol_stack_add_neighbor(SmolStackPtr, CIpAddress, CEthernetAddress);
+    extern "C" SmolError smol_stack_add_default_v4_gateway(SmolStackPtr, CIpv4Address);
+    extern "C" SmolError smol_stack_add_default_v6_gateway(SmolStackPtr, CIpv6Address);
+    extern "C" SmolError smol_stack_set_addresses(SmolStackPtr, const CIpv4Cidr *ipv4, size_t ipv4Len, const CIpv6Cidr *ipv6, size_t ipv6Len);
+    extern "C" SmolError smol_stack_add_route(SmolStackPtr, CIpCidr cidr, CIpAddress viaRouter);
+    extern "C" SmolError smol_stack_remove_route(SmolStackPtr, CIpCidr cidr);
+    extern "C" SmolError smol_stack_add_dns_server(SmolStackPtr, CIpAddress address);
+    extern "C" SmolError smol_stack_dns_resolve(SmolStackPtr, const char *name, void (*callback)(SmolError, const CIpAddress *, size_t, void *), void *context);
+    extern "C" SmolError smol_stack_enable_dhcpv4(SmolStackPtr, void (*callback)(const CDhcpLease *, void *), void *context);
+    extern "C" SmolError smol_stack_finalize(SmolStackPtr);
+    extern "C" SmolError smol_stack_virtual_tun_send(SmolStackPtr, const uint8_t *data, size_t len);
+    extern "C" SmolError smol_stack_virtual_tun_receive_wait(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
+    extern "C" SmolError smol_stack_virtual_tun_receive_instantly(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
+    extern "C" SmolError smol_stack_virtual_tun_receive_into(SmolStackPtr, uint8_t *buffer, size_t len, size_t *written);
+    extern "C" SmolError smol_stack_virtual_tun_drop_counters(SmolStackPtr, CDropCounters *dropCounters);
+    extern "C" SmolError smol_stack_destroy(void *);
 
     class RustSlice
     {
@@ -193,7 +406,6 @@ namespace smoltcp
 
         ~SmolOwner()
         {
-            std::cout << "~SmolOwner called" << std::endl;
             delete t;
         }
     };
@@ -207,90 +419,224 @@ namespace smoltcp
         std::random_device rd;
         std::mt19937 mt{rd()};
         std::uniform_int_distribution<int> random{49152, 49152 + 16383};
-        size_t currentHandle = 0;
         std::unordered_map<size_t, SmolSocket> smolSocketHandles;
 
+        //Keeps handle only if the stack added the socket
+        SmolSocket registerSocket(size_t handle, SmolError result, SmolError *error)
+        {
+            if (error)
+                *error = result;
+            SmolSocket smolSocket;
+            smolSocket.handle = handle;
+            if (result == SmolError::Ok)
+                smolSocketHandles[handle] = smolSocket;
+            return smolSocket;
+        }
+
     public:
         enum StackType
         {
             VirtualTun,
+            VirtualTap,
             Tun,
             Tap
         };
 
-        TunSmolStack(std::string interfaceName, StackType stackType)
+        /*
+            Throws std::runtime_error if the interface couldn't be created.
+            virtualTunOptions is only used by VirtualTun, nullptr means defaults
+        */
+        TunSmolStack(std::string interfaceName, StackType stackType, const CVirtualTunOptions *virtualTunOptions = nullptr)
         {
+            SmolError r = SmolError::InvalidArgument;
             if (stackType == StackType::VirtualTun)
             {
-                smolStackPtr = smol_stack_smol_stack_new_virtual_tun(interfaceName.c_str());
+                r = smol_stack_smol_stack_new_virtual_tun(interfaceName.c_str(), virtualTunOptions, &smolStackPtr);
             }
             else if (stackType == StackType::Tun)
             {
-                smolStackPtr = smol_stack_smol_stack_new_tun(interfaceName.c_str());
+                r = smol_stack_smol_stack_new_tun(interfaceName.c_str(), &smolStackPtr);
             }
             else if (stackType == StackType::Tap)
             {
-                smolStackPtr = smol_stack_smol_stack_new_tap(interfaceName.c_str());
+                r = smol_stack_smol_stack_new_tap(interfaceName.c_str(), &smolStackPtr);
+            }
+            else if (stackType == StackType::VirtualTap)
+            {
+                throw std::runtime_error("VirtualTap stacks are created with an Ethernet address");
+            }
+            if (r != SmolError::Ok)
+            {
+                throw std::runtime_error("couldn't create smol stack: " + lastErrorMessage());
             }
         }
 
-        size_t getNewHandle()
+        /*
+            In-memory TAP: like VirtualTun but virtualTunSend and virtualTunReceive*
+            carry Ethernet frames. ethernetAddress must be unicast. An mtu of 0
+            in virtualTunOptions means 1514, which includes the Ethernet header
+        */
+        TunSmolStack(std::string interfaceName, CEthernetAddress ethernetAddress, const CVirtualTunOptions *virtualTunOptions = nullptr)
         {
-            if (currentHandle < std::numeric_limits<size_t>::max())
+            SmolError r = smol_stack_smol_stack_new_virtual_tap(interfaceName.c_str(), virtualTunOptions, ethernetAddress, &smolStackPtr);
+            if (r != SmolError::Ok)
             {
-                currentHandle += 1;
-                return currentHandle;
+                throw std::runtime_error("couldn't create smol stack: " + lastErrorMessage());
             }
-            else
+        }
+
+        //Message of the last error returned on this thread
+        static std::string lastErrorMessage()
+        {
+            const char *message = smol_stack_last_error_message();
+            if (message == nullptr)
             {
-                throw std::runtime_error("Reached handle too big, you're using too much sockets\n");
+                return std::string();
             }
+            return std::string(message);
         }
 
-        SmolSocket addSocket(uint8_t socketType)
+        /*
+            Handles come from Rust because sockets for accepted
+            connections are also given handles there
+        */
+        size_t getNewHandle()
+        {
+            SocketHandle handle = 0;
+            smol_stack_new_socket_handle_key(smolStackPtr, &handle);
+            return handle;
+        }
+
+        /*
+            error (if not null) gets the result. The socket is only usable
+            when it's SmolError::Ok, otherwise the stack doesn't know its handle
+        */
+        SmolSocket addSocket(uint8_t socketType, SmolError *error = nullptr)
         {
             size_t handle = getNewHandle();
-            uint8_t result = smol_stack_add_socket(smolStackPtr, socketType, handle);
-            SmolSocket smolSocket;
-            smolSocket.handle = handle;
-            smolSocketHandles[handle] = smolSocket;
-            return smolSocket;
+            SmolError result = smol_stack_add_socket(smolStackPtr, socketType, handle);
+            return registerSocket(handle, result, error);
+        }
+
+        //Lets many tunnelled connections use smaller buffers than the defaults
+        SmolSocket addSocket(uint8_t socketType, CSocketOptions options, SmolError *error = nullptr)
+        {
+            size_t handle = getNewHandle();
+            SmolError result = smol_stack_add_socket_with_options(smolStackPtr, socketType, handle, &options);
+            return registerSocket(handle, result, error);
+        }
+
+        /*
+            Raw socket for IP protocol number protocol (47 for GRE, for example).
+            ipVersion is 4 or 6. Whole IP packets, header included, are sent
+            and received. error as in addSocket
+        */
+        SmolSocket addRawSocket(uint8_t ipVersion, uint8_t protocol, CSocketOptions options = CSocketOptions(), SmolError *error = nullptr)
+        {
+            size_t handle = getNewHandle();
+            SmolError result = smol_stack_add_raw_socket(smolStackPtr, ipVersion, protocol, handle, &options);
+            return registerSocket(handle, result, error);
+        }
+
+        /*
+            Callbacks run from spin (the poller thread after start), once the
+            stack is unlocked, see CSocketCallbacks. Readable and writable are
+            edge triggered: they run when the socket goes from not ready to ready
+        */
+        SmolError setSocketCallbacks(SmolSocket smolSocket, const CSocketCallbacks &callbacks)
+        {
+            return smol_stack_set_socket_callbacks(smolStackPtr, smolSocket.handle, &callbacks);
         }
 
-        void poll()
+        SmolError clearSocketCallbacks(SmolSocket smolSocket)
         {
-            smol_stack_poll(smolStackPtr);
+            return smol_stack_set_socket_callbacks(smolStackPtr, smolSocket.handle, nullptr);
         }
 
-        void spin(SmolSocket smolSocket)
+        //Sends FIN once everything queued on the socket was sent
+        bool close(SmolSocket smolSocket)
         {
-            smol_stack_spin(smolStackPtr, smolSocket.handle);
+            return smol_stack_socket_close(smolStackPtr, smolSocket.handle) == SmolError::Ok;
         }
 
-        void spinAll()
+        /*
+            TCP only. Sends FIN once everything queued was sent, but keeps
+            receiving until receive reports SmolError::EndOfStream
+        */
+        SmolError tcpShutdownWrite(SmolSocket smolSocket)
+        {
+            return smol_stack_tcp_shutdown_write(smolStackPtr, smolSocket.handle);
+        }
+
+        //Sends RST and drops everything queued on the socket
+        bool abort(SmolSocket smolSocket)
         {
-            smol_stack_spin_all(smolStackPtr);
+            return smol_stack_socket_abort(smolStackPtr, smolSocket.handle) == SmolError::Ok;
+        }
+
+        /*
+            Frees the socket on Rust side. Its handle can't be used anymore.
+            Removing a listener also aborts the connections it didn't hand to accept yet
+        */
+        bool remove(SmolSocket smolSocket)
+        {
+            SmolError r = smol_stack_socket_remove(smolStackPtr, smolSocket.handle);
+            smolSocketHandles.erase(smolSocket.handle);
+            return r == SmolError::Ok;
+        }
+
+        SmolError poll()
+        {
+            return smol_stack_poll(smolStackPtr);
+        }
+
+        SmolError spin(SmolSocket smolSocket)
+        {
+            return smol_stack_spin(smolStackPtr, smolSocket.handle);
+        }
+
+        SmolError spinAll()
+        {
+            return smol_stack_spin_all(smolStackPtr);
+        }
+
+        /*
+            Runs poll, spinAll and the wait on a Rust thread, so there's
+            no need for a loop here. Call after finalize. The other
+            methods can still be called from any thread meanwhile
+        */
+        SmolError start()
+        {
+            return smol_stack_start(smolStackPtr);
+        }
+
+        //Also done on destruction
+        SmolError stop()
+        {
+            return smol_stack_stop(smolStackPtr);
         }
 
         /*
             On the act of send, we specify the handle for the socket, the pointer do the data,
-            which is the most important type, and its lenght. For UDP and IGMP sockets we also
-            have to pass an endpoint (TCP does not need since we call connect before sending).
+            which is the most important type, and its lenght. For UDP sockets we also have to
+            pass an endpoint (TCP does not need since we call connect before sending). ICMP
+            sockets don't take this, they send with icmpSendEcho.
             Then, we pass a pointer to `SmolOwner`, which is a class that owns the object that
             owns `uint8_t* data`. We also pass the destructor function, which is the function 
             that accepts the `SmolOwner` pointer and deletes it. This function is supposed to
             be called from Rust when it does not need the data `uint8_t* data` anymore.
+            `data` is not copied, so it must stay valid and unchanged until then.
         */
         template <typename T>
-        void send(SmolSocket smolSocket, const uint8_t *data, size_t len, CIpEndpoint endpoint, SmolOwner<T> *pointerToSmolOwner, uint8_t (*smolOwnerDestructor)(void *))
+        SmolError send(SmolSocket smolSocket, const uint8_t *data, size_t len, CIpEndpoint endpoint, SmolOwner<T> *pointerToSmolOwner, uint8_t (*smolOwnerDestructor)(void *))
         {
-            smol_stack_smol_socket_send(smolStackPtr, smolSocket.handle, data, len, endpoint, static_cast<void *>(pointerToSmolOwner), smolOwnerDestructor);
+            return smol_stack_smol_socket_send(smolStackPtr, smolSocket.handle, data, len, endpoint, static_cast<void *>(pointerToSmolOwner), smolOwnerDestructor);
         }
 
         bool send_copy(SmolSocket smolSocket, const uint8_t *data, size_t len, CIpEndpoint endpoint)
         {
-            uint8_t r = smol_stack_smol_socket_send_copy(smolStackPtr, smolSocket.handle, data, len, endpoint);
-            if (r == 0)
+            SmolError r = smol_stack_smol_socket_send_copy(smolStackPtr, smolSocket.handle, data, len, endpoint);
+            if (r == SmolError::Ok)
             {
                 return true;
             }
@@ -309,8 +655,8 @@ namespace smoltcp
                 CIpv6Address{},
                 0};
 
-            uint8_t r = smol_stack_smol_socket_send_copy(smolStackPtr, smolSocket.handle, data, len, endpointNone);
-            if (r == 0)
+            SmolError r = smol_stack_smol_socket_send_copy(smolStackPtr, smolSocket.handle, data, len, endpointNone);
+            if (r == SmolError::Ok)
             {
                 return true;
             }
@@ -320,16 +666,19 @@ namespace smoltcp
             }
         }
 
-        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receive(SmolSocket smolSocket)
+        //error (if not null) tells NoData apart from EndOfStream when nothing is returned
+        std::optional<std::pair<std::shared_ptr<Buffer>, CIpEndpoint>> receive(SmolSocket smolSocket, SmolError *error = nullptr)
         {
             CBuffer cbuffer;
-            CIpAddress address;
+            CIpEndpoint endpoint;
 
-            uint8_t r = smol_stack_smol_socket_receive(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer);
-            if (r == 0)
+            SmolError r = smol_stack_smol_socket_receive(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer, &endpoint);
+            if (error)
+                *error = r;
+            if (r == SmolError::Ok)
             {
                 auto buffer = std::make_shared<Buffer>(cbuffer);
-                auto pair = std::make_pair(buffer, address);
+                auto pair = std::make_pair(buffer, endpoint);
                 return std::optional<decltype(pair)>(pair);
             }
             else
@@ -338,21 +687,17 @@ namespace smoltcp
             }
         }
 
-        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receiveWait(SmolSocket smolSocket)
+        //std::nullopt once nothing more can be received (closed, reset or removed)
+        std::optional<std::pair<std::shared_ptr<Buffer>, CIpEndpoint>> receiveWait(SmolSocket smolSocket)
         {
-            //std::cout << "receiveWait" << std::endl;    
             CBuffer cbuffer;
-            CIpAddress address;
+            CIpEndpoint endpoint;
 
-            uint8_t r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer, &address);
-            if (r == 0)
+            SmolError r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer, &endpoint);
+            if (r == SmolError::Ok)
             {
-                //printBufferBeggining(cbuffer.data, cbuffer.len, 5);
-                //std::cout << "...";
-                //printBufferEnd(cbuffer.data, cbuffer.len, 5);
-
                 auto buffer = std::make_shared<Buffer>(cbuffer);
-                auto pair = std::make_pair(buffer, address);
+                auto pair = std::make_pair(buffer, endpoint);
                 return std::optional<decltype(pair)>(pair);
             }
             else
@@ -364,18 +709,41 @@ namespace smoltcp
          /*
             Use your own custom allocator. Might be useful specially for ZLMediaKit which requires a buffer terminated with a \0
         */
-        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receiveWait(SmolSocket smolSocket, uint8_t *(*custom_allocator)(size_t))
+        std::optional<std::pair<std::shared_ptr<Buffer>, CIpEndpoint>> receiveWait(SmolSocket smolSocket, uint8_t *(*custom_allocator)(size_t))
+        {
+            CBuffer cbuffer;
+            CIpEndpoint endpoint;
+
+            SmolError r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, custom_allocator, &endpoint);
+            if (r == SmolError::Ok)
+            {
+                auto buffer = std::make_shared<Buffer>(cbuffer);
+                auto pair = std::make_pair(buffer, endpoint);
+                return std::optional<decltype(pair)>(pair);
+            }
+            else
+            {
+                return std::nullopt;
+            }
+        }
+
+        /*
+            Gives up after timeout with SmolError::Timeout. error (if not null)
+            tells why nothing was returned: Timeout, EndOfStream, ConnectionReset,
+            SocketClosed or SocketNotFound
+        */
+        std::optional<std::pair<std::shared_ptr<Buffer>, CIpEndpoint>> receiveWait(SmolSocket smolSocket, milliseconds timeout, SmolError *error = nullptr)
         {
             CBuffer cbuffer;
-            CIpAddress address;
+            CIpEndpoint endpoint;
 
-            uint8_t r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, custom_allocator, &address);
-            if (r == 0)
+            SmolError r = smol_stack_smol_socket_receive_wait_timeout(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer, &endpoint, timeout.count());
+            if (error)
+                *error = r;
+            if (r == SmolError::Ok)
             {
-                //std::cout << "#(" << cbuffer.len << ") - ";
-                //Utils::compactBufferPrint(cbuffer.data, cbuffer.len, 5);
                 auto buffer = std::make_shared<Buffer>(cbuffer);
-                auto pair = std::make_pair(buffer, address);
+                auto pair = std::make_pair(buffer, endpoint);
                 return std::optional<decltype(pair)>(pair);
             }
             else
@@ -384,19 +752,56 @@ namespace smoltcp
             }
         }
 
+        /*
+            Copies the next packet into buffer, no allocation involved.
+            On SmolError::BufferTooSmall the packet is kept and written
+            has the size needed
+        */
+        SmolError receiveInto(SmolSocket smolSocket, uint8_t *buffer, size_t len, size_t &written, CIpEndpoint *endpoint = nullptr)
+        {
+            return smol_stack_smol_socket_receive_into(smolStackPtr, smolSocket.handle, buffer, len, &written, endpoint);
+        }
+
+        /*
+            TCP only. Reads straight from the smoltcp rx buffer. Once called,
+            receive/receiveWait don't get this socket's data anymore
+        */
+        SmolError tcpRecvSlice(SmolSocket smolSocket, uint8_t *buffer, size_t len, size_t &written)
+        {
+            return smol_stack_tcp_recv_slice(smolStackPtr, smolSocket.handle, buffer, len, &written);
+        }
+
+        //TCP only, see TcpOption for what value means
+        SmolError tcpSetOption(SmolSocket smolSocket, TcpOption option, uint64_t value)
+        {
+            return smol_stack_tcp_set_option(smolStackPtr, smolSocket.handle, option, value);
+        }
+
+        //TCP only, for diagnostics
+        std::optional<CTcpInfo> tcpInfo(SmolSocket smolSocket)
+        {
+            CTcpInfo info;
+            SmolError r = smol_stack_tcp_get_info(smolStackPtr, smolSocket.handle, &info);
+            if (r == SmolError::Ok)
+                return info;
+            else
+                return std::nullopt;
+        }
+
         bool maySend(SmolSocket smolSocket)
         {
-            uint8_t r = smol_stack_smol_socket_may_send(smolStackPtr, smolSocket.handle);
-            if (r == 0)
-                return true;
+            uint8_t maySend = 0;
+            SmolError r = smol_stack_smol_socket_may_send(smolStackPtr, smolSocket.handle, &maySend);
+            if (r == SmolError::Ok)
+                return maySend == 1;
             else
                 return false;
         }
 
         bool connect(SmolSocket smolSocket, CIpAddress address, uint16_t src_port, uint16_t dst_port)
         {
-            uint8_t r = smol_stack_tcp_connect(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
-            if (r == 0)
+            SmolError r = smol_stack_tcp_connect(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
+            if (r == SmolError::Ok)
             {
                 return true;
             }
@@ -406,10 +811,72 @@ namespace smoltcp
             }
         }
 
+        bool listen(SmolSocket smolSocket, uint16_t port)
+        {
+            SmolError r = smol_stack_tcp_listen(smolStackPtr, smolSocket.handle, port);
+            if (r == SmolError::Ok)
+            {
+                return true;
+            }
+            else
+            {
+                return false;
+            }
+        }
+
+        //Returns a socket for the next incoming connection on a listening socket, if any
+        std::optional<SmolSocket> accept(SmolSocket listener)
+        {
+            SocketHandle handle;
+            SmolError r = smol_stack_tcp_accept(smolStackPtr, listener.handle, &handle);
+            if (r == SmolError::Ok)
+            {
+                SmolSocket smolSocket;
+                smolSocket.handle = handle;
+                smolSocketHandles[handle] = smolSocket;
+                return smolSocket;
+            }
+            else
+            {
+                return std::nullopt;
+            }
+        }
+
+        //Port 0 binds to a random ephemeral port
+        bool udpBind(SmolSocket smolSocket, uint16_t port)
+        {
+            SmolError r = smol_stack_udp_bind(smolStackPtr, smolSocket.handle, port);
+            if (r == SmolError::Ok)
+            {
+                return true;
+            }
+            else
+            {
+                return false;
+            }
+        }
+
+        //An ICMP socket only receives replies to requests carrying its ident
+        SmolError icmpBind(SmolSocket smolSocket, uint16_t ident)
+        {
+            return smol_stack_icmp_bind(smolStackPtr, smolSocket.handle, ident);
+        }
+
+        SmolError icmpSendEcho(SmolSocket smolSocket, CIpAddress address, uint16_t seqNo, const uint8_t *data = nullptr, size_t len = 0)
+        {
+            return smol_stack_icmp_send_echo(smolStackPtr, smolSocket.handle, address, seqNo, data, len);
+        }
+
+        //SmolError::NoData if no reply arrived yet
+        SmolError icmpReceiveEcho(SmolSocket smolSocket, CIcmpEchoReply &reply)
+        {
+            return smol_stack_icmp_receive_echo(smolStackPtr, smolSocket.handle, &reply);
+        }
+
         bool connectIpv4(SmolSocket smolSocket, CIpv4Address address, uint16_t src_port, uint16_t dst_port)
         {
-            uint8_t r = smol_stack_tcp_connect_ipv4(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
-            if (r == 0)
+            SmolError r = smol_stack_tcp_connect_ipv4(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
+            if (r == SmolError::Ok)
             {
                 return true;
             }
@@ -426,8 +893,8 @@ namespace smoltcp
 
         bool connectIpv6(SmolSocket smolSocket, CIpv6Address address, uint16_t src_port, uint16_t dst_port)
         {
-            uint8_t r = smol_stack_tcp_connect_ipv6(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
-            if (r == 0)
+            SmolError r = smol_stack_tcp_connect_ipv6(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
+            if (r == SmolError::Ok)
             {
                 return true;
             }
@@ -437,29 +904,98 @@ namespace smoltcp
             }
         }
 
-        void addIpv4Address(CIpv4Cidr cidr)
+        SmolError addIpv4Address(CIpv4Cidr cidr)
+        {
+            return smol_stack_add_ipv4_address(smolStackPtr, cidr);
+        }
+
+        SmolError addIpv6Address(CIpv6Cidr cidr)
+        {
+            return smol_stack_add_ipv6_address(smolStackPtr, cidr);
+        }
+
+        //Tap and VirtualTap only, before finalize
+        SmolError setEthernetAddress(CEthernetAddress address)
+        {
+            return smol_stack_set_ethernet_address(smolStackPtr, address);
+        }
+
+        //Seeds the neighbour cache. Tap and VirtualTap only, before finalize
+        SmolError addNeighbor(CIpAddress address, CEthernetAddress ethernetAddress)
+        {
+            return smol_stack_add_neighbor(smolStackPtr, address, ethernetAddress);
+        }
+
+        SmolError addDefaultV4Gateway(CIpv4Address address)
+        {
+            return smol_stack_add_default_v4_gateway(smolStackPtr, address);
+        }
+
+        SmolError addDefaultV6Gateway(CIpv6Address address)
+        {
+            return smol_stack_add_default_v6_gateway(smolStackPtr, address);
+        }
+
+        /*
+            Replaces every address, also after finalize (on a reconnect that
+            pushed new ones, for example). Existing sockets are kept
+        */
+        SmolError setAddresses(const std::vector<CIpv4Cidr> &ipv4, const std::vector<CIpv6Cidr> &ipv6)
         {
-            smol_stack_add_ipv4_address(smolStackPtr, cidr);
+            return smol_stack_set_addresses(smolStackPtr, ipv4.data(), ipv4.size(), ipv6.data(), ipv6.size());
         }
 
-        void addIpv6Address(CIpv6Cidr cidr)
+        /*
+            A /0 cidr is the default route. Routes to specific prefixes only
+            send those through the VPN (split tunnel). Works before and after finalize
+        */
+        SmolError addRoute(CIpCidr cidr, CIpAddress viaRouter)
         {
-            smol_stack_add_ipv6_address(smolStackPtr, cidr);
+            return smol_stack_add_route(smolStackPtr, cidr, viaRouter);
         }
 
-        void addDefaultV4Gateway(CIpv4Address address)
+        SmolError removeRoute(CIpCidr cidr)
         {
-            smol_stack_add_default_v4_gateway(smolStackPtr, address);
+            return smol_stack_remove_route(smolStackPtr, cidr);
         }
 
-        void addDefaultV6Gateway(CIpv6Address address)
+        /*
+            Tap and VirtualTap only. The address, default gateway and DNS servers
+            come from DHCP. callback runs from poll (so from the poller thread
+            after start) with every new lease, once the stack is unlocked.
+            Errors of the client are returned by poll
+        */
+        SmolError enableDhcpv4(void (*callback)(const CDhcpLease *, void *) = nullptr, void *context = nullptr)
         {
-            smol_stack_add_default_v6_gateway(smolStackPtr, address);
+            return smol_stack_enable_dhcpv4(smolStackPtr, callback, context);
         }
 
-        void phy_wait(int64_t timestamp)
+        //For dnsResolve, besides the servers DHCP hands out
+        SmolError addDnsServer(CIpAddress address)
         {
-            smol_stack_phy_wait(smolStackPtr, timestamp);
+            return smol_stack_add_dns_server(smolStackPtr, address);
+        }
+
+        /*
+            Resolves name through the tunnel. callback runs from poll once it's done,
+            with SmolError::Ok and the addresses, NoData or Timeout. The addresses
+            are only valid during the call, which may call this stack. poll
+            returns the error if a query couldn't be sent
+        */
+        SmolError dnsResolve(const std::string &name, void (*callback)(SmolError, const CIpAddress *, size_t, void *), void *context = nullptr)
+        {
+            return smol_stack_dns_resolve(smolStackPtr, name.c_str(), callback, context);
+        }
+
+        /*
+            Waits until the stack needs to be polled again or the device has data.
+            Returns right away if something was sent, connected or closed since
+            the last poll. timestamp is currentTimeMillis(). waitedMillis, if
+            given, gets how long it actually waited
+        */
+        SmolError phy_wait(int64_t timestamp, uint64_t *waitedMillis = nullptr)
+        {
+            return smol_stack_phy_wait(smolStackPtr, timestamp, waitedMillis);
         }
 
         int64_t currentTimeMillis()
@@ -467,22 +1003,43 @@ namespace smoltcp
             return Instant::now().count();
         }
 
-        uint8_t finalize()
+        SmolError finalize()
         {
             return smol_stack_finalize(smolStackPtr);
         }
 
-        void virtualTunSend(const uint8_t *data, size_t len)
+        SmolError virtualTunSend(const uint8_t *data, size_t len)
+        {
+            return smol_stack_virtual_tun_send(smolStackPtr, data, len);
+        }
+
+        //Same as receiveInto, for packets coming out of the stack
+        SmolError virtualTunReceiveInto(uint8_t *buffer, size_t len, size_t &written)
+        {
+            return smol_stack_virtual_tun_receive_into(smolStackPtr, buffer, len, &written);
+        }
+
+        //Packets dropped so far by full VirtualTun queues
+        std::optional<CDropCounters> virtualTunDropCounters()
         {
-            smol_stack_virtual_tun_send(smolStackPtr, data, len);
+            CDropCounters dropCounters;
+            SmolError r = smol_stack_virtual_tun_drop_counters(smolStackPtr, &dropCounters);
+            if (r == SmolError::Ok)
+            {
+                return dropCounters;
+            }
+            else
+            {
+                return std::nullopt;
+            }
         }
 
         std::optional<std::shared_ptr<Buffer>> virtualTunReceiveWait()
         {
             CBuffer cbuffer;
 
-            uint8_t r = smol_stack_virtual_tun_receive_wait(smolStackPtr, &cbuffer, &cpp_allocate_buffer);
-            if (r == 0)
+            SmolError r = smol_stack_virtual_tun_receive_wait(smolStackPtr, &cbuffer, &cpp_allocate_buffer);
+            if (r == SmolError::Ok)
             {
                 auto buffer = std::make_shared<Buffer>(cbuffer);
                 return buffer;
@@ -497,8 +1054,8 @@ namespace smoltcp
         {
             CBuffer cbuffer;
 
-            uint8_t r = smol_stack_virtual_tun_receive_instantly(smolStackPtr, &cbuffer, &cpp_allocate_buffer);
-            if (r == 0)
+            SmolError r = smol_stack_virtual_tun_receive_instantly(smolStackPtr, &cbuffer, &cpp_allocate_buffer);
+            if (r == SmolError::Ok)
             {
                 auto buffer = std::make_shared<Buffer>(cbuffer);
                 return buffer;
@@ -519,8 +1076,8 @@ namespace smoltcp
         {
             CBuffer cbuffer;
 
-            uint8_t r = smol_stack_virtual_tun_receive_callback(smolStackPtr, &cbuffer, &cpp_allocate_buffer);
-            if (r == 0)
+            SmolError r = smol_stack_virtual_tun_receive_callback(smolStackPtr, &cbuffer, &cpp_allocate_buffer);
+            if (r == SmolError::Ok)
             {
                 auto buffer = Buffer(cbuffer);
                 return buffer;
@@ -535,7 +1092,6 @@ namespace smoltcp
 
         ~TunSmolStack()
         {
-            std::cout << "TunSmolStack destruction" << std::endl;
             smol_stack_destroy(smolStackPtr);
         }
     };
@@ -1,22 +1,34 @@
 extern crate rand;
 
-use super::smol_stack::SmolSocket;
-use super::smol_stack::{Blob, Packet, SmolStack, SocketType};
+use super::error::{ffi_guard, last_error_message, write_out, CSmolError, SmolError, SmolResult};
+use super::poller::{Poller, SharedStack};
+use super::smol_stack::{SmolSocket, SmolSocketReceiver};
+use super::smol_stack::{
+    Blob, BlobData, DhcpLease, EchoReply, Event, Packet, SmolStack, SocketType, TcpOption,
+};
+use super::virtual_tun::VirtualTapInterface as VirtualTapDevice;
 use super::virtual_tun::VirtualTunInterface as VirtualTunDevice;
+use super::virtual_tun::{DropPolicy, QueueLimits};
 use smoltcp::phy::wait as phy_wait;
 use smoltcp::phy::TapInterface as TapDevice;
 use smoltcp::phy::TunInterface as TunDevice;
 use smoltcp::phy::TunInterface;
-use smoltcp::socket::{SocketHandle, TcpSocket};
+use smoltcp::phy::{Checksum, ChecksumCapabilities, DeviceCapabilities};
+use smoltcp::socket::{SocketHandle, TcpSocket, TcpState};
 use smoltcp::time::Instant;
-use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv6Address};
+use smoltcp::wire::{
+    EthernetAddress, IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Address,
+    Ipv6Address,
+};
 use std::collections::VecDeque;
 use std::ffi::{c_void, CStr};
 use std::os::raw::{c_char, c_int};
 use std::os::unix::io::AsRawFd;
+use std::ptr;
 use std::slice;
 use std::str::{self};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::time::Duration;
 
 pub enum SmolSocketType {
@@ -39,6 +51,7 @@ pub struct CBuffer {
 */
 pub enum SmolStackType<'a, 'b: 'a, 'c: 'a + 'b> {
     VirtualTun(SmolStack<'a, 'b, 'c, VirtualTunDevice>),
+    VirtualTap(SmolStack<'a, 'b, 'c, VirtualTapDevice>),
     Tun(SmolStack<'a, 'b, 'c, TunDevice>),
     Tap(SmolStack<'a, 'b, 'c, TapDevice>),
 }
@@ -50,54 +63,255 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> Drop for SmolStackType<'a, 'b, 'c> {
     }
 }
 
+/*
+    The stack holds Rc's (inside smoltcp devices and tokens) and raw
+    pointers to C++ owners, none of which leave the stack. It's only
+    ever used behind SharedSmolStack's mutex, so moving it to the
+    poller thread is fine
+*/
+unsafe impl<'a, 'b: 'a, 'c: 'a + 'b> Send for SmolStackType<'a, 'b, 'c> {}
+
+/*
+    What C++ holds as SmolStackPtr. Every exported function locks
+    the stack, so it can be used from any thread, including while
+    the poller thread started by smol_stack_start is running
+*/
+pub struct SharedSmolStack {
+    stack: SharedStack,
+    poller: Mutex<Option<Poller>>,
+}
+
+impl SharedSmolStack {
+    pub fn new(smol_stack: SmolStackType<'static, 'static, 'static>) -> SharedSmolStack {
+        SharedSmolStack {
+            stack: Arc::new(Mutex::new(smol_stack)),
+            poller: Mutex::new(None),
+        }
+    }
+
+    pub fn lock(&self) -> SmolResult<MutexGuard<SmolStackType<'static, 'static, 'static>>> {
+        self.stack.lock().map_err(|_| {
+            SmolError::new(
+                CSmolError::Panic,
+                "a panic happened while the stack was in use, it can't be used anymore",
+            )
+        })
+    }
+
+    fn lock_poller(&self) -> SmolResult<MutexGuard<Option<Poller>>> {
+        self.poller.lock().map_err(|_| {
+            SmolError::new(CSmolError::Panic, "a panic happened while starting or stopping the poller")
+        })
+    }
+
+    /*
+        Runs f with the stack locked, then fires the callbacks it
+        queued once it's unlocked, so they can call into the stack
+    */
+    pub fn run_with_events<F>(&self, f: F) -> SmolResult<()>
+    where
+        F: FnOnce(&mut SmolStackType<'static, 'static, 'static>) -> SmolResult<()>,
+    {
+        let (result, events) = {
+            let mut smol_stack = self.lock()?;
+            let result = f(&mut *smol_stack);
+            (result, smol_stack.take_events())
+        };
+        for event in events {
+            event.fire();
+        }
+        result
+    }
+
+    pub fn start(&self) -> SmolResult<()> {
+        let mut poller = self.lock_poller()?;
+        if poller.is_some() {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "poller thread is already running",
+            ));
+        }
+        let has_data = {
+            let mut smol_stack = self.lock()?;
+            //Fails if the stack is not finalized
+            smol_stack.poll_delay()?;
+            smol_stack.has_data()
+        };
+        *poller = Some(Poller::start(self.stack.clone(), has_data));
+        Ok(())
+    }
+
+    //Does nothing if the poller thread is not running
+    pub fn stop(&self) -> SmolResult<()> {
+        let poller = self.lock_poller()?.take();
+        //Dropping stops and joins the thread
+        drop(poller);
+        Ok(())
+    }
+
+    pub fn is_polling(&self) -> SmolResult<bool> {
+        Ok(self.lock_poller()?.is_some())
+    }
+
+    /*
+        Waits until the interface needs to be polled again, counting
+        from timestamp (milliseconds, like smoltcp's Instant), or until
+        the device has data. Tun/Tap wait on their file descriptor,
+        VirtualTun/VirtualTap on has_data. The stack isn't held while
+        waiting. Returns how long it actually waited
+    */
+    pub fn phy_wait(&self, timestamp: i64) -> SmolResult<Duration> {
+        let started = std::time::Instant::now();
+        let mut smol_stack = self.lock()?;
+        let delay = smol_stack.poll_delay_at(Instant::from_millis(timestamp))?;
+        /*
+            Unlike the poller, the caller released the stack between its
+            poll and this call. Anything that notified in between (send,
+            connect, close, virtual_tun_send...) bumped has_data's count,
+            and nobody was waiting to see it
+        */
+        let polled_notifications = smol_stack.polled_notifications();
+        match (smol_stack.fd(), smol_stack.has_data()) {
+            (Some(fd), has_data) => {
+                if let Some(has_data) = has_data {
+                    if *has_data.0.lock().unwrap() != polled_notifications {
+                        return Ok(started.elapsed());
+                    }
+                }
+                drop(smol_stack);
+                phy_wait(fd, delay.map(|delay| smoltcp::time::Duration::from_millis(delay.as_millis() as u64)))
+                    .map_err(|e| SmolError::new(CSmolError::Device, format!("wait error: {}", e)))?;
+            }
+            (None, Some(has_data)) => {
+                let (mutex, has_data_condition_variable) = &*has_data;
+                //Taken before releasing the stack so no notification is missed, as in the poller
+                let guard = mutex.lock().unwrap();
+                let pending = *guard != polled_notifications || smol_stack.has_packets_from_outside();
+                drop(smol_stack);
+                if pending {
+                    return Ok(started.elapsed());
+                }
+                match delay {
+                    Some(delay) => {
+                        let _ = has_data_condition_variable.wait_timeout(guard, delay);
+                    }
+                    None => {
+                        let _ = has_data_condition_variable.wait(guard);
+                    }
+                }
+            }
+            (None, None) => {
+                return Err(SmolError::new(CSmolError::Device, "device has nothing to wait on"));
+            }
+        }
+        Ok(started.elapsed())
+    }
+}
+
 impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
-    pub fn new_virtual_tun(interface_name: String) -> Box<SmolStackType<'a, 'b, 'c>> {
+    pub fn new_virtual_tun(
+        interface_name: String,
+        options: CVirtualTunOptions,
+    ) -> SmolResult<Box<SmolStackType<'a, 'b, 'c>>> {
         let packets_from_inside = Arc::new(Mutex::new(VecDeque::new()));
         let packets_from_outside = Arc::new(Mutex::new(VecDeque::new()));
-        let has_data = Arc::new((Mutex::new(()), Condvar::new()));
+        let has_data = Arc::new((Mutex::new(0), Condvar::new()));
+        let queue_limits = options.queue_limits()?;
         let device = VirtualTunDevice::new(
             interface_name.as_str(),
+            options.capabilities()?,
+            queue_limits.clone(),
             packets_from_inside.clone(),
             packets_from_outside.clone(),
             has_data.clone(),
-        )
-        .unwrap();
+        )?;
         let smol_stack = SmolStack::new(
             device,
             None,
             Some(packets_from_inside.clone()),
             Some(packets_from_outside.clone()),
+            Some(queue_limits),
+            Some(has_data.clone()),
+        );
+        Ok(Box::new(SmolStackType::VirtualTun(smol_stack)))
+    }
+
+    /*
+        Same queues as VirtualTun, but carrying Ethernet frames.
+        An mtu of 0 in options means 1514, a 1500 bytes IP packet
+        plus the Ethernet header
+    */
+    pub fn new_virtual_tap(
+        interface_name: String,
+        options: CVirtualTunOptions,
+        ethernet_address: CEthernetAddress,
+    ) -> SmolResult<Box<SmolStackType<'a, 'b, 'c>>> {
+        let mut options = options;
+        if options.mtu == 0 {
+            options.mtu = 1514;
+        }
+        let packets_from_inside = Arc::new(Mutex::new(VecDeque::new()));
+        let packets_from_outside = Arc::new(Mutex::new(VecDeque::new()));
+        let has_data = Arc::new((Mutex::new(0), Condvar::new()));
+        let queue_limits = options.queue_limits()?;
+        let device = VirtualTapDevice::new(
+            interface_name.as_str(),
+            options.capabilities()?,
+            queue_limits.clone(),
+            packets_from_inside.clone(),
+            packets_from_outside.clone(),
+            has_data.clone(),
+        )?;
+        let mut smol_stack = SmolStack::new(
+            device,
+            None,
+            Some(packets_from_inside.clone()),
+            Some(packets_from_outside.clone()),
+            Some(queue_limits),
             Some(has_data.clone()),
         );
-        Box::new(SmolStackType::VirtualTun(smol_stack))
+        smol_stack.set_ethernet_address(ethernet_address.into())?;
+        Ok(Box::new(SmolStackType::VirtualTap(smol_stack)))
     }
 
-    pub fn new_tun(interface_name: String) -> Box<SmolStackType<'a, 'b, 'c>> {
-        let device = TunDevice::new(interface_name.as_str()).unwrap();
-        let has_data = Arc::new((Mutex::new(()), Condvar::new()));
+    pub fn new_tun(interface_name: String) -> SmolResult<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = TunDevice::new(interface_name.as_str()).map_err(|e| {
+            SmolError::new(
+                CSmolError::Device,
+                format!("couldn't open TUN interface {}: {}", interface_name, e),
+            )
+        })?;
+        let has_data = Arc::new((Mutex::new(0), Condvar::new()));
         let fd = Some(device.as_raw_fd());
         let smol_stack = SmolStack::new(
             device,
+            fd,
             None,
             None,
             None,
             Some(has_data.clone()),
         );
-        Box::new(SmolStackType::Tun(smol_stack))
+        Ok(Box::new(SmolStackType::Tun(smol_stack)))
     }
 
-    pub fn new_tap(interface_name: String) -> Box<SmolStackType<'a, 'b, 'c>> {
-        let device = TapDevice::new(interface_name.as_str()).unwrap();
-        let has_data = Arc::new((Mutex::new(()), Condvar::new()));
+    pub fn new_tap(interface_name: String) -> SmolResult<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = TapDevice::new(interface_name.as_str()).map_err(|e| {
+            SmolError::new(
+                CSmolError::Device,
+                format!("couldn't open TAP interface {}: {}", interface_name, e),
+            )
+        })?;
+        let has_data = Arc::new((Mutex::new(0), Condvar::new()));
         let fd = Some(device.as_raw_fd());
         let smol_stack = SmolStack::new(
             device,
+            fd,
             None,
             None,
             None,
             Some(has_data.clone()),
         );
-        Box::new(SmolStackType::Tap(smol_stack))
+        Ok(Box::new(SmolStackType::Tap(smol_stack)))
     }
 
     pub fn new_socket_handle_key(&mut self) -> usize {
@@ -105,21 +319,55 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.new_socket_handle_key()
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.new_socket_handle_key()
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.new_socket_handle_key(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.new_socket_handle_key(),
         }
     }
 
-    pub fn add_socket(&mut self, socket_type: SocketType, socket_handle: usize) -> u8 {
+    pub fn add_socket(
+        &mut self,
+        socket_type: SocketType,
+        socket_handle: usize,
+        options: CSocketOptions,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle, options)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle, options)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle, options)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle, options)
+            }
+        }
+    }
+
+    pub fn add_raw_socket(
+        &mut self,
+        ip_version: IpVersion,
+        protocol: IpProtocol,
+        socket_handle: usize,
+        options: CSocketOptions,
+    ) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
-                smol_stack.add_socket(socket_type, socket_handle)
+                smol_stack.add_raw_socket(ip_version, protocol, socket_handle, options)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.add_raw_socket(ip_version, protocol, socket_handle, options)
             }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
-                smol_stack.add_socket(socket_type, socket_handle)
+                smol_stack.add_raw_socket(ip_version, protocol, socket_handle, options)
             }
             &mut SmolStackType::Tap(ref mut smol_stack) => {
-                smol_stack.add_socket(socket_type, socket_handle)
+                smol_stack.add_raw_socket(ip_version, protocol, socket_handle, options)
             }
         }
     }
@@ -130,11 +378,14 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         address: CIpv4Address,
         src_port: u16,
         dst_port: u16,
-    ) -> u8 {
+    ) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
             }
@@ -150,11 +401,14 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         address: CIpAddress,
         src_port: u16,
         dst_port: u16,
-    ) -> u8 {
+    ) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
             }
@@ -164,14 +418,196 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         }
     }
     
+    pub fn tcp_listen(&mut self, socket_handle_key: usize, port: u16) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.tcp_listen(socket_handle_key, port)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.tcp_listen(socket_handle_key, port)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.tcp_listen(socket_handle_key, port)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.tcp_listen(socket_handle_key, port)
+            }
+        }
+    }
+
+    pub fn tcp_accept(&mut self, socket_handle_key: usize) -> SmolResult<usize> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.tcp_accept(socket_handle_key)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.tcp_accept(socket_handle_key)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.tcp_accept(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.tcp_accept(socket_handle_key),
+        }
+    }
+
+    pub fn udp_bind(&mut self, socket_handle_key: usize, port: u16) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.udp_bind(socket_handle_key, port)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.udp_bind(socket_handle_key, port)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+        }
+    }
+
+    pub fn icmp_bind(&mut self, socket_handle_key: usize, ident: u16) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.icmp_bind(socket_handle_key, ident)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.icmp_bind(socket_handle_key, ident)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.icmp_bind(socket_handle_key, ident),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.icmp_bind(socket_handle_key, ident),
+        }
+    }
+
+    pub fn icmp_send_echo(
+        &mut self,
+        socket_handle_key: usize,
+        address: IpAddress,
+        seq_no: u16,
+        data: &[u8],
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.icmp_send_echo(socket_handle_key, address, seq_no, data)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.icmp_send_echo(socket_handle_key, address, seq_no, data)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.icmp_send_echo(socket_handle_key, address, seq_no, data)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.icmp_send_echo(socket_handle_key, address, seq_no, data)
+            }
+        }
+    }
+
+    pub fn icmp_receive_echo(&mut self, socket_handle_key: usize) -> SmolResult<EchoReply> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.icmp_receive_echo(socket_handle_key)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.icmp_receive_echo(socket_handle_key)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.icmp_receive_echo(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.icmp_receive_echo(socket_handle_key),
+        }
+    }
+
+    pub fn set_socket_callbacks(
+        &mut self,
+        socket_handle_key: usize,
+        callbacks: Option<CSocketCallbacks>,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.set_socket_callbacks(socket_handle_key, callbacks)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.set_socket_callbacks(socket_handle_key, callbacks)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.set_socket_callbacks(socket_handle_key, callbacks)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.set_socket_callbacks(socket_handle_key, callbacks)
+            }
+        }
+    }
+
+    pub fn tcp_shutdown_write(&mut self, socket_handle_key: usize) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.tcp_shutdown_write(socket_handle_key),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.tcp_shutdown_write(socket_handle_key),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.tcp_shutdown_write(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.tcp_shutdown_write(socket_handle_key),
+        }
+    }
+
+    pub fn socket_close(&mut self, socket_handle_key: usize) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.socket_close(socket_handle_key)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.socket_close(socket_handle_key)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.socket_close(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.socket_close(socket_handle_key),
+        }
+    }
+
+    pub fn socket_abort(&mut self, socket_handle_key: usize) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.socket_abort(socket_handle_key)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.socket_abort(socket_handle_key)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.socket_abort(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.socket_abort(socket_handle_key),
+        }
+    }
+
+    pub fn socket_remove(&mut self, socket_handle_key: usize) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.socket_remove(socket_handle_key)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.socket_remove(socket_handle_key)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.socket_remove(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.socket_remove(socket_handle_key),
+        }
+    }
+
+    pub fn tcp_set_option(&mut self, socket_handle_key: usize, option: TcpOption) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.tcp_set_option(socket_handle_key, option),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.tcp_set_option(socket_handle_key, option),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.tcp_set_option(socket_handle_key, option),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.tcp_set_option(socket_handle_key, option),
+        }
+    }
+
+    pub fn tcp_info(&mut self, socket_handle_key: usize) -> SmolResult<CTcpInfo> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.tcp_info(socket_handle_key),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.tcp_info(socket_handle_key),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.tcp_info(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.tcp_info(socket_handle_key),
+        }
+    }
+
     pub fn may_send(
         &mut self,
         socket_handle_key: usize
-    ) -> u8 {
+    ) -> SmolResult<bool> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.may_send(socket_handle_key)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.may_send(socket_handle_key)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.may_send(socket_handle_key)
             }
@@ -182,11 +618,14 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
     }
     
 
-    pub fn get_smol_socket(&mut self, socket_handle_key: usize) -> Option<&mut SmolSocket> {
+    pub fn get_smol_socket(&mut self, socket_handle_key: usize) -> SmolResult<&mut SmolSocket> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.get_smol_socket(socket_handle_key)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.get_smol_socket(socket_handle_key)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.get_smol_socket(socket_handle_key)
             }
@@ -202,11 +641,14 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         address: CIpv6Address,
         src_port: u16,
         dst_port: u16,
-    ) -> u8 {
+    ) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
             }
@@ -216,27 +658,66 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         }
     }
 
-    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) {
+    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
         }
     }
 
-    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) {
+    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
         }
     }
 
-    pub fn add_default_v4_gateway(&mut self, address: CIpv4Address) {
+    pub fn set_ethernet_address(&mut self, address: CEthernetAddress) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.set_ethernet_address(address.into())
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.set_ethernet_address(address.into())
+            }
+            _ => Err(SmolError::new(
+                CSmolError::NotSupported,
+                "only Ethernet (Tap and VirtualTap) stacks have a MAC address",
+            )),
+        }
+    }
+
+    pub fn add_neighbor(
+        &mut self,
+        address: CIpAddress,
+        ethernet_address: CEthernetAddress,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.add_neighbor(address.into(), ethernet_address.into())
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.add_neighbor(address.into(), ethernet_address.into())
+            }
+            _ => Err(SmolError::new(
+                CSmolError::NotSupported,
+                "only Ethernet (Tap and VirtualTap) stacks have a neighbour cache",
+            )),
+        }
+    }
+
+    pub fn add_default_v4_gateway(&mut self, address: CIpv4Address) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.add_default_v4_gateway(address)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.add_default_v4_gateway(address)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.add_default_v4_gateway(address)
             }
@@ -246,11 +727,81 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         }
     }
 
-    pub fn add_default_v6_gateway(&mut self, address: CIpv6Address) {
+    pub fn set_addresses(&mut self, ip_addrs: Vec<IpCidr>) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_addresses(ip_addrs),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.set_addresses(ip_addrs),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.set_addresses(ip_addrs),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.set_addresses(ip_addrs),
+        }
+    }
+
+    pub fn enable_dhcpv4(
+        &mut self,
+        callback: Option<DhcpLeaseCallback>,
+        context: *mut c_void,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.enable_dhcpv4(callback, context),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.enable_dhcpv4(callback, context),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.enable_dhcpv4(callback, context),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.enable_dhcpv4(callback, context),
+        }
+    }
+
+    pub fn add_dns_server(&mut self, address: IpAddress) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_dns_server(address),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.add_dns_server(address),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_dns_server(address),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_dns_server(address),
+        }
+    }
+
+    pub fn dns_resolve(
+        &mut self,
+        name: &str,
+        callback: DnsResolveCallback,
+        context: *mut c_void,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.dns_resolve(name, callback, context)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.dns_resolve(name, callback, context)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.dns_resolve(name, callback, context),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.dns_resolve(name, callback, context),
+        }
+    }
+
+    pub fn add_route(&mut self, cidr: IpCidr, via_router: IpAddress) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_route(cidr, via_router),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.add_route(cidr, via_router),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_route(cidr, via_router),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_route(cidr, via_router),
+        }
+    }
+
+    pub fn remove_route(&mut self, cidr: IpCidr) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.remove_route(cidr),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.remove_route(cidr),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.remove_route(cidr),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.remove_route(cidr),
+        }
+    }
+
+    pub fn add_default_v6_gateway(&mut self, address: CIpv6Address) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.add_default_v6_gateway(address)
             }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.add_default_v6_gateway(address)
+            }
             &mut SmolStackType::Tun(ref mut smol_stack) => {
                 smol_stack.add_default_v6_gateway(address)
             }
@@ -260,108 +811,208 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
         }
     }
 
-    pub fn finalize(&mut self) -> u8 {
+    pub fn finalize(&mut self) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.finalize(),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.finalize(),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.finalize(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.finalize(),
         }
     }
 
-    pub fn poll(&mut self) -> u8 {
+    pub fn poll(&mut self) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.poll(),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.poll(),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.poll(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.poll(),
         }
     }
 
-    pub fn spin(&mut self, socket_handle: usize) -> u8 {
+    pub fn spin(&mut self, socket_handle: usize) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.spin(socket_handle),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.spin(socket_handle),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.spin(socket_handle),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.spin(socket_handle),
         }
     }
 
-    pub fn spin_all(&mut self) -> u8 {
+    pub fn spin_all(&mut self) -> SmolResult<()> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.spin_all(),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.spin_all(),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.spin_all(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.spin_all(),
         }
     }
 
-    pub fn phy_wait(&mut self, timestamp: i64) {
+    pub fn take_events(&mut self) -> Vec<Event> {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
-                smol_stack.phy_wait_timeout(Duration::from_millis(timestamp as u64))
-            }
-            &mut SmolStackType::Tun(ref mut smol_stack) => phy_wait(
-                smol_stack.fd.unwrap(),
-                smol_stack
-                    .interface
-                    .as_mut()
-                    .unwrap()
-                    .poll_delay(&smol_stack.sockets, Instant::from_millis(timestamp)),
-            )
-            .expect("wait error"),
-            &mut SmolStackType::Tap(ref mut smol_stack) => phy_wait(
-                smol_stack.fd.unwrap(),
-                smol_stack
-                    .interface
-                    .as_mut()
-                    .unwrap()
-                    .poll_delay(&smol_stack.sockets, Instant::from_millis(timestamp)),
-            )
-            .expect("wait error"),
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.take_events(),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.take_events(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.take_events(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.take_events(),
         }
     }
 
-    pub fn receive_wait(
-        &mut self,
-        cbuffer: *mut CBuffer,
-        allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    ) -> u8 {
+    pub fn poll_delay(&mut self) -> SmolResult<Option<Duration>> {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_wait(cbuffer, allocate_function),
-            _ => panic!("receive is only for VirtualTun")
-            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
-            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.poll_delay(),
         }
     }
 
-    pub fn receive_instantly(
-        &mut self,
-        cbuffer: *mut CBuffer,
-        allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    ) -> u8 {
+    pub fn poll_delay_at(&mut self, timestamp: Instant) -> SmolResult<Option<Duration>> {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_instantly(cbuffer, allocate_function),
-            _ => panic!("receive is only for VirtualTun")
-            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
-            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.poll_delay_at(timestamp),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.poll_delay_at(timestamp),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.poll_delay_at(timestamp),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.poll_delay_at(timestamp),
         }
     }
 
-    pub fn send(&mut self, blob: Blob) -> u8 {
+    pub fn drop_counters(&self) -> SmolResult<CDropCounters> {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.send(blob),
-            _ => panic!("send is only for VirtualTun")
-            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
-            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            &SmolStackType::VirtualTun(ref smol_stack) => {
+                let dropped = smol_stack.drop_counters()?;
+                Ok(CDropCounters {
+                    from_inside: dropped.from_inside.load(Ordering::Relaxed),
+                    from_outside: dropped.from_outside.load(Ordering::Relaxed),
+                })
+            }
+            &SmolStackType::VirtualTap(ref smol_stack) => {
+                let dropped = smol_stack.drop_counters()?;
+                Ok(CDropCounters {
+                    from_inside: dropped.from_inside.load(Ordering::Relaxed),
+                    from_outside: dropped.from_outside.load(Ordering::Relaxed),
+                })
+            }
+            _ => Err(SmolError::virtual_tun_only()),
         }
     }
-}
 
-#[repr(C)]
-pub struct CIpv4Address {
-    pub address: [u8; 4],
-}
+    pub fn has_data(&self) -> Option<Arc<(Mutex<u64>, Condvar)>> {
+        match self {
+            &SmolStackType::VirtualTun(ref smol_stack) => smol_stack.has_data(),
+            &SmolStackType::VirtualTap(ref smol_stack) => smol_stack.has_data(),
+            &SmolStackType::Tun(ref smol_stack) => smol_stack.has_data(),
+            &SmolStackType::Tap(ref smol_stack) => smol_stack.has_data(),
+        }
+    }
 
-#[repr(C)]
-pub struct CIpAddress {
-    pub is_ipv4: u8,
+    pub fn polled_notifications(&self) -> u64 {
+        match self {
+            &SmolStackType::VirtualTun(ref smol_stack) => smol_stack.polled_notifications(),
+            &SmolStackType::VirtualTap(ref smol_stack) => smol_stack.polled_notifications(),
+            &SmolStackType::Tun(ref smol_stack) => smol_stack.polled_notifications(),
+            &SmolStackType::Tap(ref smol_stack) => smol_stack.polled_notifications(),
+        }
+    }
+
+    pub fn has_packets_from_outside(&self) -> bool {
+        match self {
+            &SmolStackType::VirtualTun(ref smol_stack) => smol_stack.has_packets_from_outside(),
+            &SmolStackType::VirtualTap(ref smol_stack) => smol_stack.has_packets_from_outside(),
+            &SmolStackType::Tun(ref smol_stack) => smol_stack.has_packets_from_outside(),
+            &SmolStackType::Tap(ref smol_stack) => smol_stack.has_packets_from_outside(),
+        }
+    }
+
+    pub fn fd(&self) -> Option<i32> {
+        match self {
+            &SmolStackType::VirtualTun(ref smol_stack) => smol_stack.fd,
+            &SmolStackType::VirtualTap(ref smol_stack) => smol_stack.fd,
+            &SmolStackType::Tun(ref smol_stack) => smol_stack.fd,
+            &SmolStackType::Tap(ref smol_stack) => smol_stack.fd,
+        }
+    }
+
+    pub fn receive_wait(
+        &mut self,
+        cbuffer: *mut CBuffer,
+        allocate_function: extern "C" fn(size: usize) -> *mut u8,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_wait(cbuffer, allocate_function),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.receive_wait(cbuffer, allocate_function),
+            _ => Err(SmolError::virtual_tun_only()),
+            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+        }
+    }
+
+    pub fn receive_into(&mut self, buffer: *mut u8, len: usize, written: *mut usize) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.receive_into(buffer, len, written)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.receive_into(buffer, len, written)
+            }
+            _ => Err(SmolError::virtual_tun_only()),
+        }
+    }
+
+    pub fn tcp_recv_slice(
+        &mut self,
+        socket_handle_key: usize,
+        buffer: *mut u8,
+        len: usize,
+        written: *mut usize,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.tcp_recv_slice(socket_handle_key, buffer, len, written)
+            }
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => {
+                smol_stack.tcp_recv_slice(socket_handle_key, buffer, len, written)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.tcp_recv_slice(socket_handle_key, buffer, len, written)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.tcp_recv_slice(socket_handle_key, buffer, len, written)
+            }
+        }
+    }
+
+    pub fn receive_instantly(
+        &mut self,
+        cbuffer: *mut CBuffer,
+        allocate_function: extern "C" fn(size: usize) -> *mut u8,
+    ) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_instantly(cbuffer, allocate_function),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.receive_instantly(cbuffer, allocate_function),
+            _ => Err(SmolError::virtual_tun_only()),
+            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+        }
+    }
+
+    pub fn send(&mut self, blob: Blob) -> SmolResult<()> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.send(blob),
+            &mut SmolStackType::VirtualTap(ref mut smol_stack) => smol_stack.send(blob),
+            _ => Err(SmolError::virtual_tun_only()),
+            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CIpv4Address {
+    pub address: [u8; 4],
+}
+
+#[repr(C)]
+pub struct CIpAddress {
+    pub is_ipv4: u8,
     pub ipv4_address: CIpv4Address,
     pub ipv6_address: CIpv6Address,
 
@@ -390,6 +1041,29 @@ impl Into<IpAddress> for CIpv4Address {
 }
 
 
+impl From<IpAddress> for CIpAddress {
+    fn from(address: IpAddress) -> CIpAddress {
+        let mut c_address = CIpAddress {
+            is_ipv4: 0,
+            ipv4_address: CIpv4Address { address: [0; 4] },
+            ipv6_address: CIpv6Address { address: [0; 8] },
+        };
+        match address {
+            IpAddress::Ipv4(address) => {
+                c_address.is_ipv4 = 1;
+                c_address.ipv4_address.address = address.0;
+            }
+            IpAddress::Ipv6(address) => {
+                for (i, segment) in address.0.chunks(2).enumerate() {
+                    c_address.ipv6_address.address[i] = u16::from_be_bytes([segment[0], segment[1]]);
+                }
+            }
+            _ => {}
+        }
+        c_address
+    }
+}
+
 impl Into<IpAddress> for CIpAddress {
     fn into(self) -> IpAddress {
         if self.is_ipv4==1 {
@@ -401,6 +1075,7 @@ impl Into<IpAddress> for CIpAddress {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct CIpv6Address {
     pub address: [u16; 8],
 }
@@ -438,7 +1113,7 @@ impl Into<IpAddress> for CIpv6Address {
 //Warning: keep this synced with CIpEndpointType on interface.h
 static CIpEndpoint_NONE: u8 = 0;
 static CIPENDPOINT_IPV4: u8 = 1;
-static CIPENDPOINT_IPV6: u8 = 0;
+static CIPENDPOINT_IPV6: u8 = 2;
 
 #[repr(C)]
 pub struct CIpEndpoint {
@@ -448,6 +1123,45 @@ pub struct CIpEndpoint {
     pub port: u16,
 }
 
+impl CIpEndpoint {
+    pub fn none() -> CIpEndpoint {
+        CIpEndpoint {
+            endpoint_type: CIpEndpoint_NONE,
+            ipv4: CIpv4Address { address: [0; 4] },
+            ipv6: CIpv6Address { address: [0; 8] },
+            port: 0,
+        }
+    }
+}
+
+impl From<Option<IpEndpoint>> for CIpEndpoint {
+    fn from(endpoint: Option<IpEndpoint>) -> CIpEndpoint {
+        let mut c_endpoint = CIpEndpoint::none();
+        match endpoint {
+            Some(IpEndpoint {
+                addr: IpAddress::Ipv4(address),
+                port,
+            }) => {
+                c_endpoint.endpoint_type = CIPENDPOINT_IPV4;
+                c_endpoint.ipv4.address = address.0;
+                c_endpoint.port = port;
+            }
+            Some(IpEndpoint {
+                addr: IpAddress::Ipv6(address),
+                port,
+            }) => {
+                c_endpoint.endpoint_type = CIPENDPOINT_IPV6;
+                for (i, segment) in address.0.chunks(2).enumerate() {
+                    c_endpoint.ipv6.address[i] = u16::from_be_bytes([segment[0], segment[1]]);
+                }
+                c_endpoint.port = port;
+            }
+            _ => {}
+        }
+        c_endpoint
+    }
+}
+
 impl Into<Option<IpEndpoint>> for CIpEndpoint {
     fn into(self) -> Option<IpEndpoint> {
         if self.endpoint_type == CIPENDPOINT_IPV4 {
@@ -480,79 +1194,519 @@ impl Into<Option<IpEndpoint>> for CIpEndpoint {
     }
 }
 
+//Warning: keep this synced with CIcmpEchoReply on interface.h
+#[repr(C)]
+pub struct CIcmpEchoReply {
+    pub source: CIpAddress,
+    pub seq_no: u16,
+    pub rtt_millis: u64,
+    pub payload_len: usize,
+}
+
+impl From<EchoReply> for CIcmpEchoReply {
+    fn from(reply: EchoReply) -> CIcmpEchoReply {
+        CIcmpEchoReply {
+            source: CIpAddress::from(reply.source),
+            seq_no: reply.seq_no,
+            rtt_millis: reply.rtt.as_millis() as u64,
+            payload_len: reply.payload_len,
+        }
+    }
+}
+
 #[repr(C)]
+pub struct CEthernetAddress {
+    pub address: [u8; 6],
+}
+
+impl Into<EthernetAddress> for CEthernetAddress {
+    fn into(self) -> EthernetAddress {
+        EthernetAddress(self.address)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct CIpv4Cidr {
     pub address: CIpv4Address,
     pub prefix: u8,
 }
 
+impl Into<IpCidr> for CIpv4Cidr {
+    fn into(self) -> IpCidr {
+        IpCidr::new(Into::<IpAddress>::into(self.address), self.prefix)
+    }
+}
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct CIpv6Cidr {
     pub address: CIpv6Address,
     pub prefix: u8,
 }
 
+impl Into<IpCidr> for CIpv6Cidr {
+    fn into(self) -> IpCidr {
+        IpCidr::new(Into::<IpAddress>::into(self.address), self.prefix)
+    }
+}
+
+/*
+    Called from poll with every new DHCP lease, once the stack
+    is unlocked again, so it may call smol_stack_* functions
+*/
+pub type DhcpLeaseCallback = extern "C" fn(lease: *const CDhcpLease, context: *mut c_void);
+
+pub type SocketEventCallback = extern "C" fn(socket_handle_key: usize, context: *mut c_void);
+pub type SocketErrorCallback =
+    extern "C" fn(socket_handle_key: usize, error: CSmolError, context: *mut c_void);
+
+/*
+    Run from spin when the socket changes. Any of them may be null.
+    on_connected and on_closed are TCP only. on_error gets ConnectionReset
+    when a TCP connection is reset or times out, or the error spin
+    returned. They run once spin unlocked the stack, so they may call
+    smol_stack_* functions.
+    Warning: keep this synced with CSocketCallbacks on interface.h
+*/
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CSocketCallbacks {
+    pub on_connected: Option<SocketEventCallback>,
+    pub on_readable: Option<SocketEventCallback>,
+    pub on_writable: Option<SocketEventCallback>,
+    pub on_closed: Option<SocketEventCallback>,
+    pub on_error: Option<SocketErrorCallback>,
+    //Passed back untouched to every callback
+    pub context: *mut c_void,
+}
+
+/*
+    Called from poll when a dns_resolve finishes, with Ok, NoData or
+    Timeout. addresses is only valid during the call. The stack is
+    unlocked by then, so it may call smol_stack_* functions
+*/
+pub type DnsResolveCallback = extern "C" fn(
+    result: CSmolError,
+    addresses: *const CIpAddress,
+    len: usize,
+    context: *mut c_void,
+);
+
+//Warning: keep this synced with CTcpState on interface.h
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum CTcpState {
+    Closed = 0,
+    Listen = 1,
+    SynSent = 2,
+    SynReceived = 3,
+    Established = 4,
+    FinWait1 = 5,
+    FinWait2 = 6,
+    CloseWait = 7,
+    Closing = 8,
+    LastAck = 9,
+    TimeWait = 10,
+}
+
+impl From<TcpState> for CTcpState {
+    fn from(state: TcpState) -> CTcpState {
+        match state {
+            TcpState::Closed => CTcpState::Closed,
+            TcpState::Listen => CTcpState::Listen,
+            TcpState::SynSent => CTcpState::SynSent,
+            TcpState::SynReceived => CTcpState::SynReceived,
+            TcpState::Established => CTcpState::Established,
+            TcpState::FinWait1 => CTcpState::FinWait1,
+            TcpState::FinWait2 => CTcpState::FinWait2,
+            TcpState::CloseWait => CTcpState::CloseWait,
+            TcpState::Closing => CTcpState::Closing,
+            TcpState::LastAck => CTcpState::LastAck,
+            TcpState::TimeWait => CTcpState::TimeWait,
+        }
+    }
+}
+
+/*
+    Endpoints are None (CIpEndpointType) while not connected.
+    tx/rx_queued_bytes are in smoltcp's buffers, to_send_len and
+    received_len count the packets waiting in SmolSocket's queues.
+    rtt_millis is the smoothed round trip time of the data sent,
+    0 until some of it was acknowledged.
+    Warning: keep this synced with CTcpInfo on interface.h
+*/
+#[repr(C)]
+pub struct CTcpInfo {
+    pub state: CTcpState,
+    pub local_endpoint: CIpEndpoint,
+    pub remote_endpoint: CIpEndpoint,
+    pub tx_queued_bytes: usize,
+    pub rx_queued_bytes: usize,
+    pub to_send_len: usize,
+    pub received_len: usize,
+    pub rtt_millis: u64,
+}
+
+//Warning: keep this synced with CDhcpLease on interface.h
+#[repr(C)]
+pub struct CDhcpLease {
+    pub has_address: u8,
+    pub address: CIpv4Cidr,
+    pub has_router: u8,
+    pub router: CIpv4Address,
+    pub dns_servers_len: u8,
+    pub dns_servers: [CIpv4Address; 3],
+}
+
+impl From<DhcpLease> for CDhcpLease {
+    fn from(lease: DhcpLease) -> CDhcpLease {
+        let mut c_lease = CDhcpLease {
+            has_address: 0,
+            address: CIpv4Cidr {
+                address: CIpv4Address { address: [0; 4] },
+                prefix: 0,
+            },
+            has_router: 0,
+            router: CIpv4Address { address: [0; 4] },
+            dns_servers_len: 0,
+            dns_servers: [CIpv4Address { address: [0; 4] }; 3],
+        };
+        if let Some(cidr) = lease.address {
+            c_lease.has_address = 1;
+            c_lease.address.address.address = cidr.address().0;
+            c_lease.address.prefix = cidr.prefix_len();
+        }
+        if let Some(router) = lease.router {
+            c_lease.has_router = 1;
+            c_lease.router.address = router.0;
+        }
+        for dns_server in lease.dns_servers.iter().filter_map(|dns_server| *dns_server) {
+            c_lease.dns_servers[c_lease.dns_servers_len as usize].address = dns_server.0;
+            c_lease.dns_servers_len += 1;
+        }
+        c_lease
+    }
+}
+
+//Either family, like CIpAddress
+#[repr(C)]
+pub struct CIpCidr {
+    pub is_ipv4: u8,
+    pub ipv4_cidr: CIpv4Cidr,
+    pub ipv6_cidr: CIpv6Cidr,
+}
+
+impl Into<IpCidr> for CIpCidr {
+    fn into(self) -> IpCidr {
+        if self.is_ipv4 == 1 {
+            self.ipv4_cidr.into()
+        } else {
+            self.ipv6_cidr.into()
+        }
+    }
+}
+
+pub static CHECKSUM_BOTH: u8 = 0;
+pub static CHECKSUM_TX: u8 = 1;
+pub static CHECKSUM_RX: u8 = 2;
+pub static CHECKSUM_NONE: u8 = 3;
+
+pub static DROP_POLICY_TAIL_DROP: u8 = 0;
+pub static DROP_POLICY_ERROR: u8 = 1;
+
+/*
+    Device settings for a VirtualTun stack. mtu 0 means 1500 and
+    max_burst_size 0 means no limit. checksum is one of CHECKSUM_*
+    and applies to every protocol: it says whether smoltcp computes
+    checksums on sent packets (TX) and verifies them on received
+    ones (RX). Tunnels that already check integrity can use NONE.
+    The queue capacities are in packets, 0 meaning unbounded. The inside
+    queue holds packets from smoltcp until C++ receives them, the
+    outside one holds packets from C++ until smoltcp reads them.
+    drop_policy is one of DROP_POLICY_* and says what happens to
+    packets arriving at a full queue
+*/
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CVirtualTunOptions {
+    pub mtu: usize,
+    pub max_burst_size: usize,
+    pub checksum: u8,
+    pub inside_queue_capacity: usize,
+    pub outside_queue_capacity: usize,
+    pub drop_policy: u8,
+}
+
+impl Default for CVirtualTunOptions {
+    fn default() -> CVirtualTunOptions {
+        CVirtualTunOptions {
+            mtu: 0,
+            max_burst_size: 0,
+            checksum: CHECKSUM_BOTH,
+            inside_queue_capacity: 0,
+            outside_queue_capacity: 0,
+            drop_policy: DROP_POLICY_TAIL_DROP,
+        }
+    }
+}
+
+impl CVirtualTunOptions {
+    pub fn capabilities(&self) -> SmolResult<DeviceCapabilities> {
+        let checksum = match self.checksum {
+            c if c == CHECKSUM_BOTH => Checksum::Both,
+            c if c == CHECKSUM_TX => Checksum::Tx,
+            c if c == CHECKSUM_RX => Checksum::Rx,
+            c if c == CHECKSUM_NONE => Checksum::None,
+            c => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("unknown checksum behaviour {}", c),
+                ))
+            }
+        };
+        let mut checksum_capabilities = ChecksumCapabilities::default();
+        checksum_capabilities.ipv4 = checksum.clone();
+        checksum_capabilities.udp = checksum.clone();
+        checksum_capabilities.tcp = checksum.clone();
+        checksum_capabilities.icmpv4 = checksum.clone();
+        checksum_capabilities.icmpv6 = checksum;
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.max_transmission_unit = if self.mtu == 0 { 1500 } else { self.mtu };
+        capabilities.max_burst_size = if self.max_burst_size == 0 {
+            None
+        } else {
+            Some(self.max_burst_size)
+        };
+        capabilities.checksum = checksum_capabilities;
+        Ok(capabilities)
+    }
+
+    pub fn queue_limits(&self) -> SmolResult<QueueLimits> {
+        let drop_policy = match self.drop_policy {
+            p if p == DROP_POLICY_TAIL_DROP => DropPolicy::TailDrop,
+            p if p == DROP_POLICY_ERROR => DropPolicy::Error,
+            p => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("unknown drop policy {}", p),
+                ))
+            }
+        };
+        let mut queue_limits = QueueLimits::unbounded();
+        queue_limits.inside_capacity = self.inside_queue_capacity;
+        queue_limits.outside_capacity = self.outside_queue_capacity;
+        queue_limits.drop_policy = drop_policy;
+        Ok(queue_limits)
+    }
+}
+
+//Packets dropped by full VirtualTun queues, see CVirtualTunOptions
+#[repr(C)]
+pub struct CDropCounters {
+    pub from_inside: u64,
+    pub from_outside: u64,
+}
+
+/*
+    Buffer sizes for a new socket. A field left as 0 takes
+    the default. Packet slots are the number of datagrams
+    a UDP, ICMP or raw buffer holds, and are ignored for TCP
+*/
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CSocketOptions {
+    pub rx_buffer_size: usize,
+    pub tx_buffer_size: usize,
+    pub rx_packet_slots: usize,
+    pub tx_packet_slots: usize,
+}
+
+impl Default for CSocketOptions {
+    fn default() -> CSocketOptions {
+        CSocketOptions {
+            rx_buffer_size: 0,
+            tx_buffer_size: 0,
+            rx_packet_slots: 0,
+            tx_packet_slots: 0,
+        }
+    }
+}
+
+impl CSocketOptions {
+    //Replaces the fields left as 0 with the defaults for socket_type
+    pub fn or_defaults(&self, socket_type: &SocketType) -> CSocketOptions {
+        let (default_buffer_size, default_packet_slots) = match socket_type {
+            SocketType::TCP => (65000, 0),
+            _ => (65535, 64),
+        };
+        let or_default = |value: usize, default: usize| if value == 0 { default } else { value };
+        CSocketOptions {
+            rx_buffer_size: or_default(self.rx_buffer_size, default_buffer_size),
+            tx_buffer_size: or_default(self.tx_buffer_size, default_buffer_size),
+            rx_packet_slots: or_default(self.rx_packet_slots, default_packet_slots),
+            tx_packet_slots: or_default(self.tx_packet_slots, default_packet_slots),
+        }
+    }
+}
+
+//Slice of len elements at pointer, which may be null only if len is 0
+fn slice_from_c<'a, T>(pointer: *const T, len: usize) -> SmolResult<&'a [T]> {
+    if len == 0 {
+        Ok(&[])
+    } else if pointer.is_null() {
+        Err(SmolError::new(CSmolError::InvalidArgument, "array pointer is null"))
+    } else {
+        Ok(unsafe { slice::from_raw_parts(pointer, len) })
+    }
+}
+
+/*
+    Constructors check where the stack goes before building it, so a
+    null out pointer neither leaks the stack nor opens the device
+*/
+fn check_stack_out(smol_stack: *mut *mut SharedSmolStack) -> SmolResult<()> {
+    if smol_stack.is_null() {
+        return Err(SmolError::new(CSmolError::InvalidArgument, "null output pointer"));
+    }
+    Ok(())
+}
+
+fn interface_name_from_c(interface_name: *const c_char) -> SmolResult<String> {
+    string_from_c(interface_name, "interface name")
+}
+
+//what names the string in error messages
+fn string_from_c(string: *const c_char, what: &str) -> SmolResult<String> {
+    if string.is_null() {
+        return Err(SmolError::new(
+            CSmolError::InvalidArgument,
+            format!("{} is null", what),
+        ));
+    }
+    let c_str: &CStr = unsafe { CStr::from_ptr(string) };
+    let slice: &str = c_str.to_str().map_err(|_| {
+        SmolError::new(
+            CSmolError::InvalidArgument,
+            format!("{} is not valid UTF-8", what),
+        )
+    })?;
+    Ok(slice.to_owned())
+}
+
+/*
+    Returns the message describing the last error returned by any
+    smol_stack_* function on the calling thread, or null if none.
+    The pointer is valid until the next smol_stack_* call on that thread
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_last_error_message() -> *const c_char {
+    last_error_message()
+}
+
+/*
+    Constructors write the new stack to smol_stack, which
+    is left untouched in case of error
+*/
 #[no_mangle]
-pub extern "C" fn smol_stack_smol_stack_new_virtual_tun<'a, 'b: 'a, 'c: 'a + 'b>(
+pub extern "C" fn smol_stack_smol_stack_new_virtual_tun(
     interface_name: *const c_char,
-) -> Box<SmolStackType<'a, 'b, 'c>> {
-    let interface_name_c_str: &CStr = unsafe { CStr::from_ptr(interface_name) };
-    let interface_name_slice: &str = interface_name_c_str.to_str().unwrap();
-    let s: String = interface_name_slice.to_owned();
-    SmolStackType::new_virtual_tun(s)
+    options: *const CVirtualTunOptions,
+    smol_stack: *mut *mut SharedSmolStack,
+) -> CSmolError {
+    ffi_guard(|| {
+        check_stack_out(smol_stack)?;
+        let s: String = interface_name_from_c(interface_name)?;
+        //A null options uses the defaults
+        let options = if options.is_null() {
+            CVirtualTunOptions::default()
+        } else {
+            unsafe { *options }
+        };
+        let shared_smol_stack = SharedSmolStack::new(*SmolStackType::new_virtual_tun(s, options)?);
+        write_out(smol_stack, Box::into_raw(Box::new(shared_smol_stack)))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_stack_new_virtual_tap(
+    interface_name: *const c_char,
+    options: *const CVirtualTunOptions,
+    ethernet_address: CEthernetAddress,
+    smol_stack: *mut *mut SharedSmolStack,
+) -> CSmolError {
+    ffi_guard(|| {
+        check_stack_out(smol_stack)?;
+        let s: String = interface_name_from_c(interface_name)?;
+        //A null options uses the defaults
+        let options = if options.is_null() {
+            CVirtualTunOptions::default()
+        } else {
+            unsafe { *options }
+        };
+        let shared_smol_stack =
+            SharedSmolStack::new(*SmolStackType::new_virtual_tap(s, options, ethernet_address)?);
+        write_out(smol_stack, Box::into_raw(Box::new(shared_smol_stack)))
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_smol_stack_new_tun<'a, 'b: 'a, 'c: 'a + 'b>(
+pub extern "C" fn smol_stack_smol_stack_new_tun(
     interface_name: *const c_char,
-) -> Box<SmolStackType<'a, 'b, 'c>> {
-    let interface_name_c_str: &CStr = unsafe { CStr::from_ptr(interface_name) };
-    let interface_name_slice: &str = interface_name_c_str.to_str().unwrap();
-    let s: String = interface_name_slice.to_owned();
-    SmolStackType::new_tun(s)
+    smol_stack: *mut *mut SharedSmolStack,
+) -> CSmolError {
+    ffi_guard(|| {
+        check_stack_out(smol_stack)?;
+        let s: String = interface_name_from_c(interface_name)?;
+        let shared_smol_stack = SharedSmolStack::new(*SmolStackType::new_tun(s)?);
+        write_out(smol_stack, Box::into_raw(Box::new(shared_smol_stack)))
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_smol_stack_new_tap<'a, 'b: 'a, 'c: 'a + 'b>(
+pub extern "C" fn smol_stack_smol_stack_new_tap(
     interface_name: *const c_char,
-) -> Box<SmolStackType<'a, 'b, 'c>> {
-    let interface_name_c_str: &CStr = unsafe { CStr::from_ptr(interface_name) };
-    let interface_name_slice: &str = interface_name_c_str.to_str().unwrap();
-    let s: String = interface_name_slice.to_owned();
-    SmolStackType::new_tap(s)
+    smol_stack: *mut *mut SharedSmolStack,
+) -> CSmolError {
+    ffi_guard(|| {
+        check_stack_out(smol_stack)?;
+        let s: String = interface_name_from_c(interface_name)?;
+        let shared_smol_stack = SharedSmolStack::new(*SmolStackType::new_tap(s)?);
+        write_out(smol_stack, Box::into_raw(Box::new(shared_smol_stack)))
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_send(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     data: *mut u8,
     len: usize,
     endpoint: CIpEndpoint,
     pointer_to_owner: *const c_void,
     pointer_to_destructor: unsafe extern "C" fn(*const c_void) -> u8,
-) -> u8 {
-    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
-    //let packet_as_vector = unsafe { Vec::from_raw_parts(data, len, len) };
-    let mut packet_as_vector = Vec::new();
-    let slice = unsafe { slice::from_raw_parts(data, len) };
-    packet_as_vector.extend_from_slice(slice);
-    let packet = Packet {
-        blob: Blob {
-            data: packet_as_vector,
-            start: 0,
-            pointer_to_owner: Some(pointer_to_owner),
-            pointer_to_destructor: Some(pointer_to_destructor),
-        },
-        endpoint: Into::<Option<IpEndpoint>>::into(endpoint),
-    };
-    match smol_socket {
-        Some(smol_socket) => {
-            smol_socket.send(packet);
-            0
+) -> CSmolError {
+    ffi_guard(|| {
+        /*
+            No copy: data belongs to pointer_to_owner, which is only
+            destructed after smoltcp took every byte of it
+        */
+        let packet = Packet {
+            blob: Blob {
+                data: BlobData::Foreign(data as *const u8, len),
+                start: 0,
+                pointer_to_owner: Some(pointer_to_owner),
+                pointer_to_destructor: Some(pointer_to_destructor),
+            },
+            endpoint: Into::<Option<IpEndpoint>>::into(endpoint),
+        };
+        //The packet is built first so that, on error, dropping
+        //it still calls the owner's destructor
+        if data.is_null() && len > 0 {
+            return Err(SmolError::new(CSmolError::InvalidArgument, "data is null"));
         }
-        None => 1,
-    }
+        smol_stack.lock()?.get_smol_socket(socket_handle_key)?.send(packet)
+    })
 }
 
 /*
@@ -560,197 +1714,906 @@ pub extern "C" fn smol_stack_smol_socket_send(
 */
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_send_copy(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     data: *mut u8,
     len: usize,
     endpoint: CIpEndpoint,
-) -> u8 {
-    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
-    let mut packet_as_vector = Vec::new();
-    let slice = unsafe { slice::from_raw_parts(data, len) };
-    packet_as_vector.extend_from_slice(slice);
-    let packet = Packet {
-        blob: Blob {
-            data: packet_as_vector,
-            start: 0,
-            pointer_to_owner: None,
-            pointer_to_destructor: None,
-        },
-        endpoint: Into::<Option<IpEndpoint>>::into(endpoint),
-    };
-    match smol_socket {
-        Some(smol_socket) => {
-            smol_socket.send(packet);
-            0
-        }
-        None => 1,
-    }
+) -> CSmolError {
+    ffi_guard(|| {
+        let mut packet_as_vector = Vec::new();
+        let slice = slice_from_c(data, len)?;
+        packet_as_vector.extend_from_slice(slice);
+        let packet = Packet {
+            blob: Blob {
+                data: BlobData::Owned(packet_as_vector),
+                start: 0,
+                pointer_to_owner: None,
+                pointer_to_destructor: None,
+            },
+            endpoint: Into::<Option<IpEndpoint>>::into(endpoint),
+        };
+        smol_stack.lock()?.get_smol_socket(socket_handle_key)?.send(packet)
+    })
 }
 
+/*
+    Fills endpoint (if not null) with the address and port the
+    packet came from. For TCP that's the connected peer.
+    Returns NoData if there's nothing to receive, and EndOfStream
+    (TCP) once the peer closed and everything it sent was received
+*/
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_receive(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     cbuffer: *mut CBuffer,
     allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    address: *mut CIpAddress
-) -> u8 {
-    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
-    match smol_socket {
-        Some(smol_socket) => smol_socket.receive(cbuffer, allocate_function, address),
-        None => 1,
-    }
+    endpoint: *mut CIpEndpoint,
+) -> CSmolError {
+    ffi_guard(|| {
+        smol_stack
+            .lock()?
+            .get_smol_socket(socket_handle_key)?
+            .receive(cbuffer, allocate_function, endpoint)
+    })
+}
+
+/*
+    Copies the next packet into buffer (len bytes long) without
+    any allocation, and writes its size to written. If the packet
+    doesn't fit, it's kept, written gets the size needed and
+    BufferTooSmall is returned. endpoint is filled as in receive
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_receive_into(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    buffer: *mut u8,
+    len: usize,
+    written: *mut usize,
+    endpoint: *mut CIpEndpoint,
+) -> CSmolError {
+    ffi_guard(|| {
+        smol_stack
+            .lock()?
+            .get_smol_socket(socket_handle_key)?
+            .receive_into(buffer, len, written, endpoint)
+    })
 }
 
+/*
+    TCP only. Reads up to len bytes straight from the smoltcp rx
+    buffer, writing how many to written. After the first call the
+    socket's data is only available through this function
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_recv_slice(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    buffer: *mut u8,
+    len: usize,
+    written: *mut usize,
+) -> CSmolError {
+    ffi_guard(|| {
+        smol_stack
+            .lock()?
+            .tcp_recv_slice(socket_handle_key, buffer, len, written)
+    })
+}
+
+/*
+    Blocks until the socket has data. Once nothing more can come it
+    returns why instead: EndOfStream (peer closed), ConnectionReset,
+    SocketClosed (closed or aborted here) or SocketNotFound (removed).
+    The stack is not held while waiting, so other threads (and the
+    poller) can use it
+*/
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_receive_wait(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     cbuffer: *mut CBuffer,
     allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    address: *mut CIpAddress
-) -> u8 {
-    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
-    match smol_socket {
-        Some(smol_socket) => smol_socket.receive_wait(cbuffer, allocate_function, address),
-        None => 1,
-    }
+    endpoint: *mut CIpEndpoint,
+) -> CSmolError {
+    ffi_guard(|| {
+        let receiver: SmolSocketReceiver =
+            smol_stack.lock()?.get_smol_socket(socket_handle_key)?.receiver();
+        receiver.receive_wait(cbuffer, allocate_function, endpoint, None)
+    })
+}
+
+//Same as receive_wait, but returns Timeout after timeout_millis without data
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_receive_wait_timeout(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    cbuffer: *mut CBuffer,
+    allocate_function: extern "C" fn(size: usize) -> *mut u8,
+    endpoint: *mut CIpEndpoint,
+    timeout_millis: u64,
+) -> CSmolError {
+    ffi_guard(|| {
+        let receiver: SmolSocketReceiver =
+            smol_stack.lock()?.get_smol_socket(socket_handle_key)?.receiver();
+        receiver.receive_wait(
+            cbuffer,
+            allocate_function,
+            endpoint,
+            Some(Duration::from_millis(timeout_millis)),
+        )
+    })
+}
+
+/*
+    TCP only. What value means depends on option:
+    0 keep-alive: interval in milliseconds, 0 disables keep-alives
+    1 timeout: milliseconds without an answer before the connection
+      is aborted, 0 disables it
+    2 nagle: 0 or 1, smoltcp doesn't implement Nagle so only 0 is accepted
+    3 hop limit: 1 to 255, 0 goes back to the default
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_set_option(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    option: u8,
+    value: u64,
+) -> CSmolError {
+    ffi_guard(|| {
+        let millis = |value: u64| {
+            if value == 0 {
+                None
+            } else {
+                Some(Duration::from_millis(value))
+            }
+        };
+        let option = match option {
+            0 => TcpOption::KeepAlive(millis(value)),
+            1 => TcpOption::Timeout(millis(value)),
+            2 => TcpOption::Nagle(value != 0),
+            3 if value <= u8::max_value() as u64 => TcpOption::HopLimit(match value {
+                0 => None,
+                hop_limit => Some(hop_limit as u8),
+            }),
+            3 => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("hop limit {} is bigger than 255", value),
+                ))
+            }
+            _ => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("unknown TCP option {}", option),
+                ))
+            }
+        };
+        smol_stack.lock()?.tcp_set_option(socket_handle_key, option)
+    })
+}
+
+//TCP only
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_get_info(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    info: *mut CTcpInfo,
+) -> CSmolError {
+    ffi_guard(|| {
+        let tcp_info = smol_stack.lock()?.tcp_info(socket_handle_key)?;
+        write_out(info, tcp_info)
+    })
 }
 
+//Writes 1 to may_send if the socket can send now, 0 otherwise
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_may_send(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
-) -> u8 {
-    smol_stack.may_send(socket_handle_key)
+    may_send: *mut u8,
+) -> CSmolError {
+    ffi_guard(|| {
+        let r = smol_stack.lock()?.may_send(socket_handle_key)?;
+        write_out(may_send, r as u8)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_new_socket_handle_key(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: *mut usize,
+) -> CSmolError {
+    ffi_guard(|| write_out(socket_handle_key, smol_stack.lock()?.new_socket_handle_key()))
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_add_socket(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_type: u8,
     socket_handle: usize,
-) -> u8 {
-    match socket_type {
-        0 => smol_stack.add_socket(SocketType::TCP, socket_handle),
-        1 => smol_stack.add_socket(SocketType::UDP, socket_handle),
-        _ => panic!("wrong type"),
-    }
+) -> CSmolError {
+    smol_stack_add_socket_with_options(smol_stack, socket_type, socket_handle, ptr::null())
+}
+
+/*
+    Same as smol_stack_add_socket, but with the buffer sizes
+    in options. A null options uses the defaults
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_add_socket_with_options(
+    smol_stack: &SharedSmolStack,
+    socket_type: u8,
+    socket_handle: usize,
+    options: *const CSocketOptions,
+) -> CSmolError {
+    ffi_guard(|| {
+        let options = if options.is_null() {
+            CSocketOptions::default()
+        } else {
+            unsafe { *options }
+        };
+        let socket_type = match socket_type {
+            0 => SocketType::TCP,
+            1 => SocketType::UDP,
+            2 => SocketType::ICMP,
+            _ => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("unknown socket type {}", socket_type),
+                ))
+            }
+        };
+        smol_stack.lock()?.add_socket(socket_type, socket_handle, options)
+    })
+}
+
+/*
+    Adds a raw socket, which sends and receives whole IP packets of
+    protocol (the IP header's protocol/next header number). ip_version
+    is 4 or 6. Packets are sent with smol_stack_smol_socket_send and
+    received with the receive functions, with no endpoint
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_add_raw_socket(
+    smol_stack: &SharedSmolStack,
+    ip_version: u8,
+    protocol: u8,
+    socket_handle: usize,
+    options: *const CSocketOptions,
+) -> CSmolError {
+    ffi_guard(|| {
+        let options = if options.is_null() {
+            CSocketOptions::default()
+        } else {
+            unsafe { *options }
+        };
+        let ip_version = match ip_version {
+            4 => IpVersion::Ipv4,
+            6 => IpVersion::Ipv6,
+            _ => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("unknown IP version {}", ip_version),
+                ))
+            }
+        };
+        smol_stack.lock()?.add_raw_socket(
+            ip_version,
+            IpProtocol::from(protocol),
+            socket_handle,
+            options,
+        )
+    })
+}
+
+/*
+    Callbacks run from spin (so from the poller thread after
+    smol_stack_start) when the socket changes. callbacks is copied,
+    null removes them
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_set_socket_callbacks(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    callbacks: *const CSocketCallbacks,
+) -> CSmolError {
+    ffi_guard(|| {
+        let callbacks = if callbacks.is_null() {
+            None
+        } else {
+            Some(unsafe { *callbacks })
+        };
+        smol_stack.lock()?.set_socket_callbacks(socket_handle_key, callbacks)
+    })
+}
+
+/*
+    TCP only. Sends FIN once everything queued was sent, nothing more
+    can be sent after this. Receiving goes on until EndOfStream
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_shutdown_write(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.tcp_shutdown_write(socket_handle_key))
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_socket_close(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.socket_close(socket_handle_key))
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_phy_wait(smol_stack: &mut SmolStackType, timestamp: i64) {
-    smol_stack.phy_wait(timestamp)
+pub extern "C" fn smol_stack_socket_abort(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.socket_abort(socket_handle_key))
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_socket_remove(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.socket_remove(socket_handle_key))
+}
+
+/*
+    timestamp is the current time in milliseconds (Instant::now() in C++).
+    If waited_millis is not null, it gets how long the call actually waited
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_phy_wait(
+    smol_stack: &SharedSmolStack,
+    timestamp: i64,
+    waited_millis: *mut u64,
+) -> CSmolError {
+    ffi_guard(|| {
+        //The poller thread already does the waiting
+        if smol_stack.is_polling()? {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "phy_wait can't be used while the poller thread is running",
+            ));
+        }
+        let waited = smol_stack.phy_wait(timestamp)?;
+        if waited_millis.is_null() {
+            return Ok(());
+        }
+        write_out(waited_millis, waited.as_millis() as u64)
+    })
+}
+
+/*
+    Starts a thread that polls the stack, spins all sockets and
+    waits for the next event, so C++ doesn't need its own loop.
+    The stack must be finalized
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_start(smol_stack: &SharedSmolStack) -> CSmolError {
+    ffi_guard(|| smol_stack.start())
+}
+
+//Stops the poller thread and waits for it to finish
+#[no_mangle]
+pub extern "C" fn smol_stack_stop(smol_stack: &SharedSmolStack) -> CSmolError {
+    ffi_guard(|| smol_stack.stop())
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_tcp_connect(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     address: CIpAddress,
     src_port: u16,
     dst_port: u16,
-) -> u8 {
-    smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.tcp_connect(socket_handle_key, address, src_port, dst_port))
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_listen(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    port: u16,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.tcp_listen(socket_handle_key, port))
+}
+
+/*
+    Writes the key of the next incoming connection on the listening
+    socket to accepted_socket_handle_key. Returns NoData if there's no
+    connection waiting to be accepted
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_accept(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    accepted_socket_handle_key: *mut usize,
+) -> CSmolError {
+    ffi_guard(|| {
+        let key = smol_stack.lock()?.tcp_accept(socket_handle_key)?;
+        write_out(accepted_socket_handle_key, key)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_udp_bind(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    port: u16,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.udp_bind(socket_handle_key, port))
+}
+
+//ICMP sockets must be bound to an identifier before sending echo requests
+#[no_mangle]
+pub extern "C" fn smol_stack_icmp_bind(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    ident: u16,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.icmp_bind(socket_handle_key, ident))
+}
+
+/*
+    Sends an echo request with data (copied, may be null if len is 0)
+    as payload. Replies are read with smol_stack_icmp_receive_echo
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_icmp_send_echo(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    address: CIpAddress,
+    seq_no: u16,
+    data: *const u8,
+    len: usize,
+) -> CSmolError {
+    ffi_guard(|| {
+        let data = slice_from_c(data, len)?;
+        smol_stack
+            .lock()?
+            .icmp_send_echo(socket_handle_key, address.into(), seq_no, data)
+    })
+}
+
+//Returns NoData if no echo reply arrived yet
+#[no_mangle]
+pub extern "C" fn smol_stack_icmp_receive_echo(
+    smol_stack: &SharedSmolStack,
+    socket_handle_key: usize,
+    reply: *mut CIcmpEchoReply,
+) -> CSmolError {
+    ffi_guard(|| {
+        let echo_reply = smol_stack.lock()?.icmp_receive_echo(socket_handle_key)?;
+        write_out(reply, CIcmpEchoReply::from(echo_reply))
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_tcp_connect_ipv4(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     address: CIpv4Address,
     src_port: u16,
     dst_port: u16,
-) -> u8 {
-    smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port))
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_tcp_connect_ipv6(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     socket_handle_key: usize,
     address: CIpv6Address,
     src_port: u16,
     dst_port: u16,
-) -> u8 {
-    smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port))
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_poll(smol_stack: &SharedSmolStack) -> CSmolError {
+    ffi_guard(|| smol_stack.run_with_events(|smol_stack| smol_stack.poll()))
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_spin(smol_stack: &SharedSmolStack, socket_handle: usize) -> CSmolError {
+    ffi_guard(|| smol_stack.run_with_events(|smol_stack| smol_stack.spin(socket_handle)))
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_poll(smol_stack: &mut SmolStackType) -> u8 {
-    smol_stack.poll()
+pub extern "C" fn smol_stack_spin_all(smol_stack: &SharedSmolStack) -> CSmolError {
+    ffi_guard(|| smol_stack.run_with_events(|smol_stack| smol_stack.spin_all()))
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_spin(smol_stack: &mut SmolStackType, socket_handle: usize) -> u8 {
-    smol_stack.spin(socket_handle)
+pub extern "C" fn smol_stack_add_ipv4_address(
+    smol_stack: &SharedSmolStack,
+    cidr: CIpv4Cidr,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_ipv4_address(cidr))
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_spin_all(smol_stack: &mut SmolStackType) -> u8 {
-    smol_stack.spin_all()
+pub extern "C" fn smol_stack_add_ipv6_address(
+    smol_stack: &SharedSmolStack,
+    cidr: CIpv6Cidr,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_ipv6_address(cidr))
 }
 
+//Ethernet stacks only, before finalize
 #[no_mangle]
-pub extern "C" fn smol_stack_add_ipv4_address(smol_stack: &mut SmolStackType, cidr: CIpv4Cidr) {
-    smol_stack.add_ipv4_address(cidr);
+pub extern "C" fn smol_stack_set_ethernet_address(
+    smol_stack: &SharedSmolStack,
+    address: CEthernetAddress,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.set_ethernet_address(address))
 }
 
+//Ethernet stacks only, before finalize
+#[no_mangle]
+pub extern "C" fn smol_stack_add_neighbor(
+    smol_stack: &SharedSmolStack,
+    address: CIpAddress,
+    ethernet_address: CEthernetAddress,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_neighbor(address, ethernet_address))
+}
+
+/*
+    Replaces all the stack's addresses with the ipv4_len addresses at ipv4
+    plus the ipv6_len at ipv6. Works before and after finalize, when the
+    running interface is changed in place and sockets are kept
+*/
 #[no_mangle]
-pub extern "C" fn smol_stack_add_ipv6_address(smol_stack: &mut SmolStackType, cidr: CIpv6Cidr) {
-    smol_stack.add_ipv6_address(cidr);
+pub extern "C" fn smol_stack_set_addresses(
+    smol_stack: &SharedSmolStack,
+    ipv4: *const CIpv4Cidr,
+    ipv4_len: usize,
+    ipv6: *const CIpv6Cidr,
+    ipv6_len: usize,
+) -> CSmolError {
+    ffi_guard(|| {
+        let mut ip_addrs = Vec::<IpCidr>::new();
+        for cidr in slice_from_c(ipv4, ipv4_len)? {
+            ip_addrs.push(cidr.clone().into());
+        }
+        for cidr in slice_from_c(ipv6, ipv6_len)? {
+            ip_addrs.push(cidr.clone().into());
+        }
+        smol_stack.lock()?.set_addresses(ip_addrs)
+    })
+}
+
+/*
+    Routes packets to cidr through via_router, replacing the route
+    to cidr if there's one. A /0 cidr is the default route.
+    Before finalize routes are staged, after it they're live
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_add_route(
+    smol_stack: &SharedSmolStack,
+    cidr: CIpCidr,
+    via_router: CIpAddress,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_route(cidr.into(), via_router.into()))
+}
+
+//cidr must be the same one passed to smol_stack_add_route
+#[no_mangle]
+pub extern "C" fn smol_stack_remove_route(
+    smol_stack: &SharedSmolStack,
+    cidr: CIpCidr,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.remove_route(cidr.into()))
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_add_default_v4_gateway(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     address: CIpv4Address,
-) {
-    smol_stack.add_default_v4_gateway(address);
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_default_v4_gateway(address))
 }
 
 #[no_mangle]
 pub extern "C" fn smol_stack_add_default_v6_gateway(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     address: CIpv6Address,
-) {
-    smol_stack.add_default_v6_gateway(address);
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_default_v6_gateway(address))
 }
 
+//DNS server for smol_stack_dns_resolve, besides the ones from DHCP
 #[no_mangle]
-pub extern "C" fn smol_stack_finalize<'a, 'b: 'a, 'c: 'a + 'b>(
-    smol_stack: &mut SmolStackType<'a, 'b, 'c>,
-) -> u8 {
-    smol_stack.finalize()
+pub extern "C" fn smol_stack_add_dns_server(
+    smol_stack: &SharedSmolStack,
+    address: CIpAddress,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.add_dns_server(address.into()))
 }
 
+/*
+    Resolves name (A and AAAA) through the stack. The queries go
+    out and are retried as the stack is polled, and callback gets
+    the addresses, with context, once it's done. The stack must have
+    a DNS server, added by hand or from DHCP. callback can't be null.
+    smol_stack_poll returns the error if a query couldn't be sent
+*/
 #[no_mangle]
-pub extern "C" fn smol_stack_destroy(_: Option<Box<SmolStackType>>) {}
+pub extern "C" fn smol_stack_dns_resolve(
+    smol_stack: &SharedSmolStack,
+    name: *const c_char,
+    callback: Option<DnsResolveCallback>,
+    context: *mut c_void,
+) -> CSmolError {
+    ffi_guard(|| {
+        let callback = callback
+            .ok_or_else(|| SmolError::new(CSmolError::InvalidArgument, "callback is null"))?;
+        let name = string_from_c(name, "name")?;
+        smol_stack.lock()?.dns_resolve(&name, callback, context)
+    })
+}
+
+/*
+    Gets the IPv4 address, default gateway and DNS servers from DHCP.
+    Tap and VirtualTap only. callback may be null, and gets context
+    back every time a new lease is installed. Errors of the client
+    are returned by smol_stack_poll
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_enable_dhcpv4(
+    smol_stack: &SharedSmolStack,
+    callback: Option<DhcpLeaseCallback>,
+    context: *mut c_void,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.enable_dhcpv4(callback, context))
+}
+
+//Default gateways are optional, an IPv4-only stack can leave out the IPv6 one
+#[no_mangle]
+pub extern "C" fn smol_stack_finalize(
+    smol_stack: &SharedSmolStack,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.finalize())
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_destroy(smol_stack: Option<Box<SharedSmolStack>>) -> CSmolError {
+    ffi_guard(|| {
+        drop(smol_stack);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_receive_wait(
+    smol_stack: &SharedSmolStack,
+    cbuffer: *mut CBuffer,
+    allocate_function: extern "C" fn(size: usize) -> *mut u8,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.receive_wait(cbuffer, allocate_function))
+}
 
 #[no_mangle]
 pub extern "C" fn smol_stack_virtual_tun_receive_instantly(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     cbuffer: *mut CBuffer,
     allocate_function: extern "C" fn(size: usize) -> *mut u8,
-) -> u8 {
-    smol_stack.receive_instantly(cbuffer, allocate_function)
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.receive_instantly(cbuffer, allocate_function))
 }
 
+//Same as smol_stack_smol_socket_receive_into, for packets coming out of the stack
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_receive_into(
+    smol_stack: &SharedSmolStack,
+    buffer: *mut u8,
+    len: usize,
+    written: *mut usize,
+) -> CSmolError {
+    ffi_guard(|| smol_stack.lock()?.receive_into(buffer, len, written))
+}
+
+/*
+    Returns QueueFull if the queue of packets sent to the
+    stack is full and the drop policy is DROP_POLICY_ERROR
+*/
 #[no_mangle]
 pub extern "C" fn smol_stack_virtual_tun_send(
-    smol_stack: &mut SmolStackType,
+    smol_stack: &SharedSmolStack,
     data: *mut u8,
     len: usize,
-) -> u8 {
-    let slice = unsafe { slice::from_raw_parts(data, len) };
-    let mut packet_as_vector = Vec::new();
-    packet_as_vector.extend_from_slice(slice);
-    let blob = Blob {
-        data: packet_as_vector,
-        start: 0,
-        pointer_to_owner: None,
-        pointer_to_destructor: None,
-    };
-    smol_stack.send(blob)
+) -> CSmolError {
+    ffi_guard(|| {
+        let slice = slice_from_c(data, len)?;
+        let mut packet_as_vector = Vec::new();
+        packet_as_vector.extend_from_slice(slice);
+        let blob = Blob {
+            data: BlobData::Owned(packet_as_vector),
+            start: 0,
+            pointer_to_owner: None,
+            pointer_to_destructor: None,
+        };
+        smol_stack.lock()?.send(blob)
+    })
+}
+
+/*
+    Writes how many packets were dropped because a VirtualTun
+    queue was full (tail drop policy)
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_drop_counters(
+    smol_stack: &SharedSmolStack,
+    drop_counters: *mut CDropCounters,
+) -> CSmolError {
+    ffi_guard(|| {
+        let counters = smol_stack.lock()?.drop_counters()?;
+        write_out(drop_counters, counters)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_endpoint_round_trip() {
+        let endpoint = IpEndpoint::new(IpAddress::v4(192, 168, 69, 1), 8080);
+        let c_endpoint = CIpEndpoint::from(Some(endpoint));
+        assert_eq!(c_endpoint.endpoint_type, CIPENDPOINT_IPV4);
+        assert_eq!(c_endpoint.ipv4.address, [192, 168, 69, 1]);
+        assert_eq!(c_endpoint.port, 8080);
+        let back: Option<IpEndpoint> = c_endpoint.into();
+        assert_eq!(back, Some(endpoint));
+    }
+
+    #[test]
+    fn ipv6_endpoint_round_trip() {
+        let endpoint = IpEndpoint::new(IpAddress::v6(0xfdaa, 0, 0, 0, 0, 0, 0, 1), 443);
+        let c_endpoint = CIpEndpoint::from(Some(endpoint));
+        assert_eq!(c_endpoint.endpoint_type, CIPENDPOINT_IPV6);
+        //Segments are kept in host order
+        assert_eq!(c_endpoint.ipv6.address, [0xfdaa, 0, 0, 0, 0, 0, 0, 1]);
+        let back: Option<IpEndpoint> = c_endpoint.into();
+        assert_eq!(back, Some(endpoint));
+    }
+
+    #[test]
+    fn no_endpoint_round_trip() {
+        let c_endpoint = CIpEndpoint::from(None);
+        assert_eq!(c_endpoint.endpoint_type, CIpEndpoint_NONE);
+        let back: Option<IpEndpoint> = c_endpoint.into();
+        assert_eq!(back, None);
+    }
+
+    #[test]
+    fn tcp_options_default_buffers_but_not_slots() {
+        let options = CSocketOptions::default().or_defaults(&SocketType::TCP);
+        assert_eq!(options.rx_buffer_size, 65000);
+        assert_eq!(options.tx_buffer_size, 65000);
+        assert_eq!(options.rx_packet_slots, 0);
+        assert_eq!(options.tx_packet_slots, 0);
+    }
+
+    #[test]
+    fn packet_socket_options_default_buffers_and_slots() {
+        for socket_type in &[SocketType::UDP, SocketType::ICMP, SocketType::RAW_IPV4, SocketType::RAW_IPV6] {
+            let options = CSocketOptions::default().or_defaults(socket_type);
+            assert_eq!(options.rx_buffer_size, 65535);
+            assert_eq!(options.tx_buffer_size, 65535);
+            assert_eq!(options.rx_packet_slots, 64);
+            assert_eq!(options.tx_packet_slots, 64);
+        }
+    }
+
+    #[test]
+    fn options_set_by_the_caller_are_kept() {
+        let options = CSocketOptions {
+            rx_buffer_size: 1500,
+            tx_buffer_size: 0,
+            rx_packet_slots: 4,
+            tx_packet_slots: 0,
+        }
+        .or_defaults(&SocketType::UDP);
+        assert_eq!(options.rx_buffer_size, 1500);
+        assert_eq!(options.tx_buffer_size, 65535);
+        assert_eq!(options.rx_packet_slots, 4);
+        assert_eq!(options.tx_packet_slots, 64);
+    }
+
+    #[test]
+    fn unknown_endpoint_type_is_none() {
+        let mut c_endpoint = CIpEndpoint::none();
+        c_endpoint.endpoint_type = 7;
+        let back: Option<IpEndpoint> = c_endpoint.into();
+        assert_eq!(back, None);
+    }
+
+    //Finalized VirtualTun stack with a UDP socket bound to port 5000
+    fn udp_stack() -> (Arc<SharedSmolStack>, usize) {
+        let smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned(), CVirtualTunOptions::default())
+            .ok()
+            .unwrap();
+        let smol_stack = Arc::new(SharedSmolStack::new(*smol_stack));
+        let socket_handle_key;
+        {
+            let mut smol_stack = smol_stack.lock().ok().unwrap();
+            smol_stack
+                .add_ipv4_address(CIpv4Cidr {
+                    address: CIpv4Address { address: [192, 168, 69, 1] },
+                    prefix: 24,
+                })
+                .ok()
+                .unwrap();
+            smol_stack.finalize().ok().unwrap();
+            socket_handle_key = smol_stack.new_socket_handle_key();
+            smol_stack
+                .add_socket(SocketType::UDP, socket_handle_key, CSocketOptions::default())
+                .ok()
+                .unwrap();
+            smol_stack.udp_bind(socket_handle_key, 5000).ok().unwrap();
+        }
+        (smol_stack, socket_handle_key)
+    }
+
+    #[test]
+    fn phy_wait_sees_a_send_queued_after_spin_all() {
+        let (smol_stack, socket_handle_key) = udp_stack();
+        {
+            let mut smol_stack = smol_stack.lock().ok().unwrap();
+            let _ = smol_stack.poll();
+            let _ = smol_stack.spin_all();
+        }
+        //Nothing in smoltcp has to be polled, so only the send can end the wait
+        let mut data = vec![1, 2, 3];
+        let endpoint = CIpEndpoint::from(Some(IpEndpoint::new(IpAddress::v4(192, 168, 69, 2), 5000)));
+        let r = smol_stack_smol_socket_send_copy(&smol_stack, socket_handle_key, data.as_mut_ptr(), data.len(), endpoint);
+        assert_eq!(r, CSmolError::Ok);
+
+        let (waited_sender, waited_receiver) = std::sync::mpsc::channel();
+        let waiting_smol_stack = smol_stack.clone();
+        std::thread::spawn(move || {
+            let waited = waiting_smol_stack.phy_wait(Instant::now().total_millis()).is_ok();
+            let _ = waited_sender.send(waited);
+        });
+        assert_eq!(waited_receiver.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+
+    static CLOSED_FROM_CALLBACK: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    extern "C" fn close_when_writable(socket_handle_key: usize, context: *mut c_void) {
+        let smol_stack = unsafe { &*(context as *const SharedSmolStack) };
+        //Deadlocks if the stack is still locked
+        let r = smol_stack_socket_close(smol_stack, socket_handle_key);
+        CLOSED_FROM_CALLBACK.store(r == CSmolError::Ok, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn callbacks_can_call_into_the_stack() {
+        let (smol_stack, socket_handle_key) = udp_stack();
+        let callbacks = CSocketCallbacks {
+            on_connected: None,
+            on_readable: None,
+            on_writable: Some(close_when_writable),
+            on_closed: None,
+            on_error: None,
+            context: &*smol_stack as *const SharedSmolStack as *mut c_void,
+        };
+        let r = smol_stack_set_socket_callbacks(&smol_stack, socket_handle_key, &callbacks);
+        assert_eq!(r, CSmolError::Ok);
+        //A bound UDP socket becomes writable on its first spin
+        assert_eq!(smol_stack_spin(&smol_stack, socket_handle_key), CSmolError::Ok);
+        assert!(CLOSED_FROM_CALLBACK.load(Ordering::SeqCst));
+    }
 }
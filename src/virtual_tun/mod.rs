@@ -5,8 +5,11 @@
 //extern crate url;
 extern crate smoltcp;
 pub mod virtual_tun;
+pub mod error;
 pub mod interface;
 pub mod smol_stack;
+pub mod poller;
+pub mod dns;
 
-pub use virtual_tun::VirtualTunInterface;
+pub use virtual_tun::{VirtualTapInterface, VirtualTunInterface};
 pub use smol_stack::SmolStack;
\ No newline at end of file
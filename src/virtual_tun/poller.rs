@@ -0,0 +1,127 @@
+use super::interface::SmolStackType;
+use super::smol_stack::notify_all;
+use smoltcp::phy::wait as phy_wait;
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/*
+    Tun/Tap devices are waited on their file descriptor, which
+    has_data can't interrupt, so the wait is capped to pick up
+    packets sent from other threads (and stop) in time
+*/
+const FD_WAIT_LIMIT: Duration = Duration::from_millis(10);
+
+pub type SharedStack = Arc<Mutex<SmolStackType<'static, 'static, 'static>>>;
+
+/*
+    Thread that does what C++ used to do by hand: poll,
+    spin_all, then sleep until the interface needs polling
+    again or has_data is notified
+*/
+pub struct Poller {
+    running: Arc<AtomicBool>,
+    has_data: Option<Arc<(Mutex<u64>, Condvar)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Poller {
+    pub fn start(stack: SharedStack, has_data: Option<Arc<(Mutex<u64>, Condvar)>>) -> Poller {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = thread::Builder::new()
+            .name("smol_stack_poller".to_owned())
+            .spawn(move || run(stack, thread_running))
+            .expect("couldn't spawn the poller thread");
+        Poller {
+            running: running,
+            has_data: has_data,
+            thread: Some(thread),
+        }
+    }
+
+    //Returns once the thread has finished its current iteration
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(has_data) = self.has_data.as_ref() {
+            notify_all(has_data);
+        }
+        if let Some(thread) = self.thread.take() {
+            //A panic on the poller thread already poisoned the stack, nothing else to do
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(stack: SharedStack, running: Arc<AtomicBool>) {
+    while running.load(Ordering::SeqCst) {
+        let mut smol_stack = match stack.lock() {
+            Ok(smol_stack) => smol_stack,
+            //Some thread panicked while using the stack
+            Err(_) => return,
+        };
+        //Errors here are about single packets or sockets, the loop goes on
+        let _ = smol_stack.poll();
+        let _ = smol_stack.spin_all();
+        /*
+            Callbacks run without the stack, as they may call into it.
+            Whatever they did is picked up by going around again
+        */
+        let events = smol_stack.take_events();
+        if !events.is_empty() {
+            drop(smol_stack);
+            for event in events {
+                event.fire();
+            }
+            continue;
+        }
+        let delay = smol_stack.poll_delay().unwrap_or(None);
+        match (smol_stack.fd(), smol_stack.has_data()) {
+            (Some(fd), _) => {
+                drop(smol_stack);
+                let delay = match delay {
+                    Some(delay) => cmp::min(delay, FD_WAIT_LIMIT),
+                    None => FD_WAIT_LIMIT,
+                };
+                let _ = phy_wait(
+                    fd,
+                    Some(smoltcp::time::Duration::from_millis(delay.as_millis() as u64)),
+                );
+            }
+            (None, Some(has_data)) => {
+                let (mutex, has_data_condition_variable) = &*has_data;
+                /*
+                    Everything that notifies has_data holds the stack and takes
+                    this mutex before notifying, so taking it before releasing
+                    the stack means no notification is missed
+                */
+                let guard = mutex.lock().unwrap();
+                drop(smol_stack);
+                //stop might have been called before we got the mutex
+                if !running.load(Ordering::SeqCst) {
+                    return;
+                }
+                match delay {
+                    Some(delay) => {
+                        let _ = has_data_condition_variable.wait_timeout(guard, delay);
+                    }
+                    None => {
+                        let _ = has_data_condition_variable.wait(guard);
+                    }
+                }
+            }
+            (None, None) => {
+                drop(smol_stack);
+                thread::sleep(delay.unwrap_or(FD_WAIT_LIMIT));
+            }
+        }
+    }
+}
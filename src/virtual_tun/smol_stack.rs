@@ -1,22 +1,34 @@
 //use smoltcp_openvpn_bridge::virtual_tun::VirtualTunInterface;
-use super::interface::{CBuffer, CIpAddress, CIpv4Address, CIpv4Cidr, CIpv6Address, CIpv6Cidr};
+use super::dns;
+use super::error::{write_out, CSmolError, SmolError, SmolResult};
+use super::interface::{
+    CBuffer, CDhcpLease, CIpAddress, CIpEndpoint, CIpv4Address, CIpv4Cidr, CIpv6Address,
+    CIpv6Cidr, CSocketCallbacks, CSocketOptions, CTcpInfo, DhcpLeaseCallback,
+    DnsResolveCallback, SocketErrorCallback, SocketEventCallback,
+};
 use super::virtual_tun::VirtualTunInterface as TunDevice;
-use smoltcp::iface::{Interface, InterfaceBuilder, Routes};
+use super::virtual_tun::{DropCounters, DropPolicy, QueueLimits};
+use smoltcp::dhcp::Dhcpv4Client;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Route, Routes};
 use smoltcp::phy::wait as phy_wait;
-use smoltcp::phy::{self, Device};
+use smoltcp::phy::{self, ChecksumCapabilities, Device, Medium};
 use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 
 use smoltcp::socket::{
-    AnySocket, RawSocket, RawSocketBuffer, Socket, SocketHandle, SocketRef, SocketSet, TcpSocket,
-    TcpSocketBuffer, UdpSocket, UdpSocketBuffer,
+    AnySocket, IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer, RawSocket,
+    RawSocketBuffer, Socket, SocketHandle, SocketRef, SocketSet, TcpSocket, TcpSocketBuffer,
+    TcpState, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
 };
 use smoltcp::storage::PacketMetadata;
 use smoltcp::time::Instant;
 use smoltcp::wire::{
-    IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv6Address,
+    EthernetAddress, Icmpv4Packet, Icmpv4Repr, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr,
+    IpEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv4Cidr, Ipv6Address,
 };
+use rand::Rng;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::VecDeque;
@@ -24,10 +36,11 @@ use std::ffi::c_void;
 use std::ptr;
 use std::rc::Rc;
 use std::slice;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Condvar, Mutex};
 use std::vec::Vec;
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum SocketType {
     RAW_IPV4,
     RAW_IPV6,
@@ -36,8 +49,40 @@ pub enum SocketType {
     UDP,
 }
 
+/*
+    Bytes of a Blob. Foreign points to memory owned by
+    pointer_to_owner (C++), which stays valid until the
+    Blob's destructor runs, so it's never copied
+*/
+pub enum BlobData {
+    Owned(Vec<u8>),
+    Foreign(*const u8, usize),
+}
+
+impl BlobData {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            &BlobData::Owned(ref data) => data.as_slice(),
+            &BlobData::Foreign(pointer, len) => {
+                if len == 0 {
+                    &[]
+                } else {
+                    unsafe { slice::from_raw_parts(pointer, len) }
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            &BlobData::Owned(ref data) => data.len(),
+            &BlobData::Foreign(_, len) => len,
+        }
+    }
+}
+
 pub struct Blob {
-    pub data: Vec<u8>,
+    pub data: BlobData,
     pub start: usize,
     //A pointer do the object (SmolOwner in C++) that owns the data on the slice
     pub pointer_to_owner: Option<*const c_void>,
@@ -54,6 +99,191 @@ pub struct Packet {
     pub endpoint: Option<IpEndpoint>,
 }
 
+//A packet received from a socket, with the endpoint it came from (UDP case)
+pub struct ReceivedPacket {
+    pub data: Vec<u8>,
+    pub endpoint: Option<IpEndpoint>,
+}
+
+//What the DHCP server handed out
+#[derive(Clone, Copy, PartialEq)]
+pub struct DhcpLease {
+    pub address: Option<Ipv4Cidr>,
+    pub router: Option<Ipv4Address>,
+    pub dns_servers: [Option<Ipv4Address>; 3],
+}
+
+pub struct Dhcp {
+    client: Dhcpv4Client,
+    callback: Option<DhcpLeaseCallback>,
+    //Passed back untouched to callback
+    context: *mut c_void,
+    //Last lease installed, callback only runs when it changes
+    lease: Option<DhcpLease>,
+}
+
+//A name being resolved by SmolStack::dns_resolve
+struct DnsQuery {
+    name: String,
+    //(id, type) of the A and AAAA queries still waiting for an answer
+    pending: Vec<(u16, u16)>,
+    addresses: Vec<IpAddress>,
+    //Some server said the name doesn't exist
+    name_error: bool,
+    attempts: usize,
+    next_send: Instant,
+    callback: DnsResolveCallback,
+    context: *mut c_void,
+}
+
+impl DnsQuery {
+    //The callback with whatever was found
+    fn finish(self) -> Event {
+        let result = if !self.addresses.is_empty() {
+            CSmolError::Ok
+        } else if self.pending.is_empty() || self.name_error {
+            CSmolError::NoData
+        } else {
+            CSmolError::Timeout
+        };
+        let addresses: Vec<CIpAddress> = self.addresses.into_iter().map(CIpAddress::from).collect();
+        Event::DnsResolved(self.callback, result, addresses, self.context)
+    }
+}
+
+/*
+    A C callback that spin or poll wants to run. They're queued while
+    the stack is held and fired by whoever unlocks it (the exported
+    functions, the poller thread), so callbacks can call into the stack
+*/
+pub enum Event {
+    Socket(SocketEventCallback, usize, *mut c_void),
+    SocketError(SocketErrorCallback, usize, CSmolError, *mut c_void),
+    DhcpLease(DhcpLeaseCallback, CDhcpLease, *mut c_void),
+    DnsResolved(DnsResolveCallback, CSmolError, Vec<CIpAddress>, *mut c_void),
+}
+
+impl Event {
+    pub fn fire(self) {
+        match self {
+            Event::Socket(callback, socket_handle_key, context) => callback(socket_handle_key, context),
+            Event::SocketError(callback, socket_handle_key, error, context) => {
+                callback(socket_handle_key, error, context)
+            }
+            Event::DhcpLease(callback, lease, context) => callback(&lease, context),
+            Event::DnsResolved(callback, result, addresses, context) => {
+                callback(result, addresses.as_ptr(), addresses.len(), context)
+            }
+        }
+    }
+}
+
+/*
+    What the socket looked like after the last spin, so callbacks
+    only run when something changes
+*/
+#[derive(Clone, Copy)]
+struct EventState {
+    tcp_state: TcpState,
+    readable: bool,
+    writable: bool,
+}
+
+impl EventState {
+    fn new() -> EventState {
+        EventState {
+            tcp_state: TcpState::Closed,
+            readable: false,
+            writable: false,
+        }
+    }
+}
+
+/*
+    smoltcp keeps its own RTT estimator private, so this times the data
+    SmolSocket hands to the TcpSocket until the peer acknowledges it.
+    One segment is timed at a time, and only when it goes out on an
+    idle buffer, so it doesn't wait behind queued data. Samples are
+    smoothed as TCP's SRTT is (RFC 6298). Retransmissions can't be told
+    apart, so a lost segment makes for a high sample
+*/
+struct RttEstimator {
+    //Bytes handed to the TcpSocket so far
+    queued: u64,
+    //(queued once it's acknowledged, when it was handed over) of the bytes being timed
+    timing: Option<(u64, Instant)>,
+    smoothed: Option<Duration>,
+}
+
+impl RttEstimator {
+    fn new() -> RttEstimator {
+        RttEstimator {
+            queued: 0,
+            timing: None,
+            smoothed: None,
+        }
+    }
+
+    //bytes were just put in a tx buffer that was empty if idle
+    fn sent(&mut self, bytes: usize, idle: bool, now: Instant) {
+        self.queued += bytes as u64;
+        if self.timing.is_none() && idle && bytes > 0 {
+            self.timing = Some((self.queued, now));
+        }
+    }
+
+    //unacked is what's left in the tx buffer (TcpSocket::send_queue)
+    fn acked(&mut self, unacked: usize, now: Instant) {
+        let (until, sent_at) = match self.timing {
+            Some(timing) => timing,
+            None => return,
+        };
+        if self.queued.saturating_sub(unacked as u64) < until {
+            return;
+        }
+        let sample = Duration::from_millis(cmp::max(now.total_millis() - sent_at.total_millis(), 0) as u64);
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => (smoothed * 7 + sample) / 8,
+            None => sample,
+        });
+        self.timing = None;
+    }
+
+    //The connection is gone, what was being timed won't be acknowledged
+    fn stop(&mut self) {
+        self.timing = None;
+    }
+
+    //0 until there's a sample
+    fn millis(&self) -> u64 {
+        self.smoothed.map_or(0, |smoothed| smoothed.as_millis() as u64)
+    }
+}
+
+//Reply to an echo request sent with SmolStack::icmp_send_echo
+pub struct EchoReply {
+    pub source: IpAddress,
+    pub seq_no: u16,
+    pub rtt: Duration,
+    //Bytes of data echoed back, not counting the send time
+    pub payload_len: usize,
+}
+
+//Settings applied to the TcpSocket behind a SmolSocket
+pub enum TcpOption {
+    //Interval between keep-alive segments on an idle connection, None disables them
+    KeepAlive(Option<Duration>),
+    //The connection is aborted if the peer doesn't answer for this long, None waits forever
+    Timeout(Option<Duration>),
+    Nagle(bool),
+    //None goes back to the interface's default
+    HopLimit(Option<u8>),
+}
+
+/*
+    A Blob is dropped once all of its bytes were written to the smoltcp
+    socket buffer (or it was refused), so a Foreign data is never read after this
+*/
 impl<'a> Drop for Blob {
     fn drop(&mut self) {
         let f = self.pointer_to_destructor;
@@ -74,28 +304,63 @@ pub struct SmolSocket {
     pub to_send: Arc<Mutex<VecDeque<Packet>>>,
     //If we couldn't send entire packet at once, hold it here for next send
     current_to_send: Option<Packet>,
-    pub received: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    pub received: Arc<Mutex<VecDeque<ReceivedPacket>>>,
     /*
         Same has_data condition variable used by SmolStack
         Used so EVERY time something is written to sockets
         the poller loop is unlocked
     */
-    has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+    has_data: Option<Arc<(Mutex<u64>, Condvar)>>,
     /*
         Specific for SmolSocket, used to unlock receive_wait, which
         is unlocked by SmolStack when new data is written to this 
         SmolSocket
     */
-    smol_socket_has_data: Arc<(Mutex<()>, Condvar)>,
+    smol_socket_has_data: Arc<(Mutex<u64>, Condvar)>,
     //The endpoint that this socket is connected to (TCP case)
     endpoint: Option<IpAddress>,
+    //Port this socket listens on (TCP server case). While set, SmolStack
+    //re-arms a fresh listening socket every time a connection comes in
+    listen_port: Option<u16>,
+    //Keys of the SmolSockets created for incoming connections, waiting for accept
+    accepted: VecDeque<usize>,
+    //Set by close. The socket is closed once everything queued is sent
+    closing: bool,
+    //Set by tcp_shutdown_write. Like closing, but received data is still delivered
+    write_shutdown: bool,
+    /*
+        Why nothing more will be put in received: EndOfStream (peer
+        sent FIN), ConnectionReset, SocketClosed (closed here) or
+        SocketNotFound (removed). Shared with SmolSocketReceiver
+    */
+    ended: Arc<Mutex<Option<CSmolError>>>,
+    //TCP state seen by the last spin, to tell resets apart from normal closes
+    last_tcp_state: TcpState,
+    //Buffer sizes, with defaults filled in. Listeners re-arm with these
+    options: CSocketOptions,
+    /*
+        Set by the first tcp_recv_slice. From then on, spin leaves
+        incoming data in the smoltcp rx buffer instead of copying
+        it to received
+    */
+    direct_receive: bool,
+    //Identifier the ICMP socket is bound to
+    icmp_ident: Option<u16>,
+    //Echo replies matching icmp_ident (ICMP case)
+    echo_replies: VecDeque<EchoReply>,
+    //Set with set_socket_callbacks, run from spin
+    callbacks: Option<CSocketCallbacks>,
+    event_state: EventState,
+    //Round trip time of the data sent (TCP case)
+    rtt: RttEstimator,
 }
 
 impl<'a> SmolSocket {
     pub fn new(
         socket_handle: SocketHandle,
         socket_type: SocketType,
-        has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+        has_data: Option<Arc<(Mutex<u64>, Condvar)>>,
+        options: CSocketOptions,
     ) -> SmolSocket {
         SmolSocket {
             socket_type: socket_type,
@@ -104,23 +369,89 @@ impl<'a> SmolSocket {
             current_to_send: None,
             received: Arc::new(Mutex::new(VecDeque::new())),
             has_data: has_data,
-            smol_socket_has_data: Arc::new((Mutex::new(()), Condvar::new())),
+            smol_socket_has_data: Arc::new((Mutex::new(0), Condvar::new())),
             endpoint: None,
+            listen_port: None,
+            accepted: VecDeque::new(),
+            closing: false,
+            write_shutdown: false,
+            ended: Arc::new(Mutex::new(None)),
+            last_tcp_state: TcpState::Closed,
+            options: options,
+            direct_receive: false,
+            icmp_ident: None,
+            echo_replies: VecDeque::new(),
+            callbacks: None,
+            event_state: EventState::new(),
+            rtt: RttEstimator::new(),
         }
     }
 
-    pub fn send(&mut self, packet: Packet) -> u8 {
-        if packet.endpoint.is_none()
-            && (self.socket_type == SocketType::UDP || self.socket_type == SocketType::ICMP)
-        {
-            panic!("this socket type needs an endpoint to send to");
+    /*
+        Returns the key of the oldest connection that arrived
+        on this listening socket, if any
+    */
+    pub fn accept(&mut self) -> Option<usize> {
+        self.accepted.pop_front()
+    }
+
+    pub fn send(&mut self, packet: Packet) -> SmolResult<()> {
+        //spin never sends what's queued on ICMP sockets, echo requests go out right away
+        if self.socket_type == SocketType::ICMP {
+            return Err(SmolError::new(
+                CSmolError::WrongSocketType,
+                "ICMP sockets send with icmp_send_echo",
+            ));
+        }
+        if packet.endpoint.is_none() && self.socket_type == SocketType::UDP {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "this socket type needs an endpoint to send to",
+            ));
+        }
+        //Nothing more goes out after close, the packet is dropped (and its owner destructed)
+        if self.closing || self.write_shutdown {
+            return Err(SmolError::new(
+                CSmolError::SocketClosed,
+                "socket was closed",
+            ));
         }
         //println!("packet being sent on SmolSocket!");
         self.to_send.lock().unwrap().push_back(packet);
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
         //Unlock the poller thread because new data is available
-        has_data_condition_variable.notify_all();
-        0
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Copies the next packet straight into buffer and writes its size
+        to written. If it doesn't fit, nothing is consumed: written gets
+        the size needed and BufferTooSmall is returned
+    */
+    pub fn receive_into(
+        &mut self,
+        buffer: *mut u8,
+        len: usize,
+        written: *mut usize,
+        endpoint: *mut CIpEndpoint,
+    ) -> SmolResult<()> {
+        check_out_buffer(buffer, len, written)?;
+        let s;
+        {
+            let ended = *self.ended.lock().unwrap();
+            let mut received = self.received.lock().unwrap();
+            let needed = match received.front() {
+                Some(s) => s.data.len(),
+                None => return Err(nothing_to_receive(ended)),
+            };
+            check_fits(needed, len, written)?;
+            s = received.pop_front().unwrap();
+        }
+        if !endpoint.is_null() {
+            unsafe { *endpoint = CIpEndpoint::from(s.endpoint) };
+        }
+        unsafe { ptr::copy_nonoverlapping(s.data.as_ptr(), buffer, s.data.len()) };
+        write_out(written, s.data.len())
     }
 
     //TODO: figure out a better way than copying. Inneficient receive
@@ -128,9 +459,11 @@ impl<'a> SmolSocket {
         &mut self,
         cbuffer: *mut CBuffer,
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
-        address: *mut CIpAddress,
-    ) -> u8 {
+        endpoint: *mut CIpEndpoint,
+    ) -> SmolResult<()> {
         let s;
+        //Read before the queue, so data pushed before ended was set isn't missed
+        let ended = *self.ended.lock().unwrap();
         {
             //Create a scope so we hold the queue for the least ammount needed
             //TODO: do I really need to create a scope?
@@ -138,75 +471,74 @@ impl<'a> SmolSocket {
         }
         match s {
             Some(s) => {
+                if !endpoint.is_null() {
+                    unsafe { *endpoint = CIpEndpoint::from(s.endpoint) };
+                }
+                let s = s.data;
                 let p: *mut u8 = allocate_function(s.len());
                 unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
-                unsafe {
-                    *cbuffer = CBuffer {
+                write_out(
+                    cbuffer,
+                    CBuffer {
                         data: p,
                         len: s.len(),
-                    };
-                }
-                //TODO:!!!!! fill CIpAddress here
-                0
+                    },
+                )
             }
-            None => 1,
+            None => Err(nothing_to_receive(ended)),
         }
     }
 
-    //TODO: figure out a better way than copying. Inneficient receive
+    /*
+        Waits until there's something to receive, or nothing more will
+        come (see ended), or timeout passes. Only use this when
+        nothing else needs the stack meanwhile, otherwise take a
+        receiver() and wait on it
+    */
     pub fn receive_wait(
         &mut self,
         cbuffer: *mut CBuffer,
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
-        address: *mut CIpAddress,
-    ) -> u8 {
-        let mut s;
-        loop {
-            {
-                s = self.received.lock().unwrap().pop_front()
-            }
-            match &s {
-                //If we have a packet, we dont need to wait, so break
-                Some(_) => {
-                    break;
-                }
-                None => {}
-            }
-            let (mutex, has_data_condition_variable) = &*self.smol_socket_has_data.as_ref().clone();
-            has_data_condition_variable.wait(mutex.lock().unwrap());
-            //let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-            //has_data_condition_variable.wait(mutex.lock().unwrap());
+        endpoint: *mut CIpEndpoint,
+        timeout: Option<Duration>,
+    ) -> SmolResult<()> {
+        self.receiver().receive_wait(cbuffer, allocate_function, endpoint, timeout)
+    }
+
+    pub fn receiver(&self) -> SmolSocketReceiver {
+        SmolSocketReceiver {
+            received: self.received.clone(),
+            smol_socket_has_data: self.smol_socket_has_data.clone(),
+            ended: self.ended.clone(),
         }
-        use std::io::{self, Write};
-        match s {
-            Some(s) => {
-                let p: *mut u8 = allocate_function(s.len());
-                if s[0]== 36 {
-                    //print!("$");
-                    //io::stdout().flush().unwrap();
-                } else {
-                    //println!("{}", s[0]);
-                }
-                //println!("bufferlen {}, len+1: {}",  s.len(),  s.len()+1);
-                unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
-                unsafe {
-                    *cbuffer = CBuffer {
-                        data: p,
-                        len: s.len(),
-                    };
-                }
-                /*
-                let (mutex, has_data_condition_variable) =
-                    &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                */
-                //TODO:!!!!! fill CIpAddress here
+    }
 
-                0
+    /*
+        Records why nothing more will be received (the first reason
+        wins) and wakes up receive_wait so reader threads can exit
+    */
+    pub fn end_receive(&self, reason: CSmolError) {
+        {
+            let mut ended = self.ended.lock().unwrap();
+            if ended.is_some() {
+                return;
             }
-            None => 1,
+            *ended = Some(reason);
         }
+        notify_all(&self.smol_socket_has_data);
+    }
+
+    pub fn has_pending_send(&self) -> bool {
+        self.current_to_send.is_some() || !self.to_send.lock().unwrap().is_empty()
+    }
+
+    /*
+        Drops everything waiting to be sent. Dropping the packets
+        calls the C++ destructors of their owners
+    */
+    pub fn discard_pending_send(&mut self) {
+        self.current_to_send = None;
+        self.to_send.lock().unwrap().clear();
     }
 
     pub fn get_latest_packet(&mut self) -> Option<Packet> {
@@ -226,6 +558,206 @@ impl<'a> SmolSocket {
     }
 }
 
+/*
+    The receiving side of a SmolSocket. It shares the socket's queue
+    and condition variable, so a thread can wait on it without holding
+    the stack, which the poller thread needs to deliver the data
+*/
+pub struct SmolSocketReceiver {
+    received: Arc<Mutex<VecDeque<ReceivedPacket>>>,
+    smol_socket_has_data: Arc<(Mutex<u64>, Condvar)>,
+    ended: Arc<Mutex<Option<CSmolError>>>,
+}
+
+impl SmolSocketReceiver {
+    //TODO: figure out a better way than copying. Inneficient receive
+    pub fn receive_wait(
+        &self,
+        cbuffer: *mut CBuffer,
+        allocate_function: extern "C" fn(size: usize) -> *mut u8,
+        endpoint: *mut CIpEndpoint,
+        timeout: Option<Duration>,
+    ) -> SmolResult<()> {
+        let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+        let s;
+        {
+            let (mutex, smol_socket_has_data_condition_variable) = &*self.smol_socket_has_data;
+            /*
+                The queue is checked with the mutex held, and SmolStack
+                notifies with it held too, so data arriving between the
+                check and the wait still wakes us up
+            */
+            let mut guard = mutex.lock().unwrap();
+            loop {
+                let ended = *self.ended.lock().unwrap();
+                match self.received.lock().unwrap().pop_front() {
+                    Some(packet) => {
+                        s = packet;
+                        break;
+                    }
+                    None if ended.is_some() => return Err(nothing_to_receive(ended)),
+                    None => {}
+                }
+                guard = match deadline {
+                    Some(deadline) => {
+                        let now = std::time::Instant::now();
+                        if now >= deadline {
+                            return Err(SmolError::new(
+                                CSmolError::Timeout,
+                                "nothing was received in time",
+                            ));
+                        }
+                        smol_socket_has_data_condition_variable
+                            .wait_timeout(guard, deadline - now)
+                            .unwrap()
+                            .0
+                    }
+                    None => smol_socket_has_data_condition_variable.wait(guard).unwrap(),
+                };
+            }
+        }
+        if !endpoint.is_null() {
+            unsafe { *endpoint = CIpEndpoint::from(s.endpoint) };
+        }
+        let s = s.data;
+        let p: *mut u8 = allocate_function(s.len());
+        unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
+        write_out(
+            cbuffer,
+            CBuffer {
+                data: p,
+                len: s.len(),
+            },
+        )
+    }
+}
+
+//NoData, or why nothing more will come once the socket ended
+fn nothing_to_receive(ended: Option<CSmolError>) -> SmolError {
+    match ended {
+        None => SmolError::new(CSmolError::NoData, "nothing to receive"),
+        Some(CSmolError::EndOfStream) => SmolError::new(CSmolError::EndOfStream, "peer closed the connection"),
+        Some(CSmolError::ConnectionReset) => {
+            SmolError::new(CSmolError::ConnectionReset, "connection was reset or timed out")
+        }
+        Some(CSmolError::SocketNotFound) => SmolError::new(CSmolError::SocketNotFound, "socket was removed"),
+        Some(code) => SmolError::new(code, "socket was closed"),
+    }
+}
+
+//The peer sent FIN, so nothing past what's in the rx buffer will come
+fn peer_sent_fin(socket: &TcpSocket) -> bool {
+    match socket.state() {
+        TcpState::CloseWait | TcpState::LastAck | TcpState::Closing | TcpState::TimeWait => true,
+        _ => false,
+    }
+}
+
+//Fails before anything is consumed if the caller's buffer or written is unusable
+fn check_out_buffer(buffer: *mut u8, len: usize, written: *mut usize) -> SmolResult<()> {
+    if written.is_null() || (buffer.is_null() && len > 0) {
+        return Err(SmolError::new(
+            CSmolError::InvalidArgument,
+            "null buffer or written pointer",
+        ));
+    }
+    Ok(())
+}
+
+//Writes the size needed to written when a packet of needed bytes doesn't fit in len
+fn check_fits(needed: usize, len: usize, written: *mut usize) -> SmolResult<()> {
+    if needed > len {
+        write_out(written, needed)?;
+        return Err(SmolError::new(
+            CSmolError::BufferTooSmall,
+            format!("packet of {} bytes doesn't fit in {} bytes", needed, len),
+        ));
+    }
+    Ok(())
+}
+
+/*
+    Notifies with the mutex held, so a thread that has just
+    checked for work and is about to wait can't miss it. The
+    mutex counts the notifications, so a thread that wasn't
+    waiting yet can still tell it missed one
+*/
+pub fn notify_all(has_data: &Arc<(Mutex<u64>, Condvar)>) {
+    let (mutex, condition_variable) = &**has_data;
+    let mut notifications = mutex.lock().unwrap();
+    *notifications = notifications.wrapping_add(1);
+    condition_variable.notify_all();
+}
+
+/*
+    Returns (ident, seq_no, send time, payload length) if data is an
+    echo reply to a request sent by icmp_send_echo. The interface
+    already verified the checksum
+*/
+fn parse_echo_reply(source: &IpAddress, data: &[u8]) -> Option<(u16, u16, i64, usize)> {
+    let checksum = ChecksumCapabilities::ignored();
+    match source {
+        &IpAddress::Ipv4(_) => {
+            let packet = Icmpv4Packet::new_checked(data).ok()?;
+            match Icmpv4Repr::parse(&packet, &checksum).ok()? {
+                Icmpv4Repr::EchoReply { ident, seq_no, data } => {
+                    Some((ident, seq_no, sent_at(data)?, data.len() - 8))
+                }
+                _ => None,
+            }
+        }
+        &IpAddress::Ipv6(_) => {
+            let packet = Icmpv6Packet::new_checked(data).ok()?;
+            match Icmpv6Repr::parse(source, &IpAddress::Unspecified, &packet, &checksum).ok()? {
+                Icmpv6Repr::EchoReply { ident, seq_no, data } => {
+                    Some((ident, seq_no, sent_at(data)?, data.len() - 8))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+//The send time icmp_send_echo puts in front of the payload
+fn sent_at(payload: &[u8]) -> Option<i64> {
+    if payload.len() < 8 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&payload[..8]);
+    Some(i64::from_be_bytes(bytes))
+}
+
+/*
+    Gets the SmolSocket for smol_socket_handle, making sure it's of
+    socket_type, because getting a smoltcp socket with the wrong
+    type panics. Takes the map instead of SmolStack so that
+    SmolStack::sockets can still be borrowed by the caller
+*/
+fn typed_smol_socket(
+    smol_sockets: &mut HashMap<usize, SmolSocket>,
+    smol_socket_handle: usize,
+    socket_type: SocketType,
+) -> SmolResult<&mut SmolSocket> {
+    match smol_sockets.get_mut(&smol_socket_handle) {
+        Some(smol_socket) => {
+            if smol_socket.socket_type == socket_type {
+                Ok(smol_socket)
+            } else {
+                Err(SmolError::new(
+                    CSmolError::WrongSocketType,
+                    format!(
+                        "socket {} is {:?}, not {:?}",
+                        smol_socket_handle, smol_socket.socket_type, socket_type
+                    ),
+                ))
+            }
+        }
+        None => Err(SmolError::socket_not_found(smol_socket_handle)),
+    }
+}
+
 pub struct SmolStack<'a, 'b: 'a, 'c: 'a + 'b, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
@@ -240,14 +772,31 @@ where
     smol_sockets: HashMap<usize, SmolSocket>,
     pub device: Option<DeviceT>,
     ip_addrs: Option<std::vec::Vec<IpCidr>>,
-    default_v4_gw: Option<Ipv4Address>,
-    default_v6_gw: Option<Ipv6Address>,
+    //Routes staged until finalize, default gateways included
+    routes: Option<BTreeMap<IpCidr, Route>>,
+    //Ethernet devices only (Tap, VirtualTap)
+    ethernet_addr: Option<EthernetAddress>,
+    //Neighbours the cache starts with, staged until finalize
+    neighbors: Option<Vec<(IpAddress, EthernetAddress)>>,
     pub interface: Option<Interface<'a, 'b, 'c, DeviceT>>,
     //For TunInterface only. Couldn't think of a way to
     //create a specialized SmolStack for this case only
     packets_from_inside: Option<Arc<Mutex<VecDeque<Vec<u8>>>>>,
     packets_from_outside: Option<Arc<Mutex<VecDeque<Blob>>>>,
-    has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+    //Same limits the VirtualTun device has, send enforces them on packets_from_outside
+    queue_limits: Option<QueueLimits>,
+    has_data: Option<Arc<(Mutex<u64>, Condvar)>>,
+    //has_data's count when poll last started, see phy_wait
+    polled_notifications: u64,
+    //Ethernet stacks that enabled DHCP only
+    dhcp: Option<Dhcp>,
+    //Set with add_dns_server. The ones from DHCP are in its lease
+    dns_servers: Vec<IpAddress>,
+    //UDP socket the resolver uses, created by the first dns_resolve
+    dns_socket: Option<SocketHandle>,
+    dns_queries: Vec<DnsQuery>,
+    //Callbacks waiting for the stack to be unlocked, see take_events
+    events: Vec<Event>,
 }
 
 impl<'a, 'b: 'a, 'c: 'a + 'b, DeviceT> SmolStack<'a, 'b, 'c, DeviceT>
@@ -259,7 +808,8 @@ where
         fd: Option<i32>,
         packets_from_inside: Option<Arc<Mutex<VecDeque<Vec<u8>>>>>,
         packets_from_outside: Option<Arc<Mutex<VecDeque<Blob>>>>,
-        has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+        queue_limits: Option<QueueLimits>,
+        has_data: Option<Arc<(Mutex<u64>, Condvar)>>,
     ) -> SmolStack<'a, 'b, 'c, DeviceT> {
         let socket_set = SocketSet::new(vec![]);
         let ip_addrs = std::vec::Vec::new();
@@ -270,67 +820,155 @@ where
             smol_sockets: HashMap::new(),
             device: Some(device),
             ip_addrs: Some(ip_addrs),
-            default_v4_gw: None,
-            default_v6_gw: None,
+            routes: Some(BTreeMap::new()),
+            ethernet_addr: None,
+            neighbors: Some(Vec::new()),
             interface: None,
             packets_from_inside: packets_from_inside,
             packets_from_outside: packets_from_outside,
+            queue_limits: queue_limits,
             has_data: has_data,
+            polled_notifications: 0,
+            dhcp: None,
+            dns_servers: Vec::new(),
+            dns_socket: None,
+            dns_queries: Vec::new(),
+            events: Vec::new(),
         }
     }
 
-    pub fn get_smol_socket(&mut self, smol_socket_handle: usize) -> Option<&mut SmolSocket> {
-        let smol_socket = self.smol_sockets.get_mut(&smol_socket_handle);
-        smol_socket
+    pub fn get_smol_socket(&mut self, smol_socket_handle: usize) -> SmolResult<&mut SmolSocket> {
+        self.smol_sockets
+            .get_mut(&smol_socket_handle)
+            .ok_or_else(|| SmolError::socket_not_found(smol_socket_handle))
     }
 
     pub fn new_socket_handle_key(&mut self) -> usize {
         //TODO: panic when usize is about to overflow
-        self.current_key += 1;
-        self.current_key
+        //Skips keys that are already taken, as accepted connections
+        //also get keys from here
+        loop {
+            self.current_key += 1;
+            if !self.smol_sockets.contains_key(&self.current_key) {
+                return self.current_key;
+            }
+        }
+    }
+
+    fn add_tcp_socket(&mut self, options: &CSocketOptions) -> SocketHandle {
+        let rx_buffer = TcpSocketBuffer::new(vec![0; options.rx_buffer_size]);
+        let tx_buffer = TcpSocketBuffer::new(vec![0; options.tx_buffer_size]);
+        let socket = TcpSocket::new(rx_buffer, tx_buffer);
+        self.sockets.add(socket)
     }
 
-    pub fn add_socket(&mut self, socket_type: SocketType, smol_socket_handle: usize) -> u8 {
+    /*
+        Fields of options left as 0 take the defaults: 65000 bytes
+        each way for TCP, and 65535 bytes with 64 datagrams each
+        way for UDP and ICMP
+    */
+    pub fn add_socket(
+        &mut self,
+        socket_type: SocketType,
+        smol_socket_handle: usize,
+        options: CSocketOptions,
+    ) -> SmolResult<()> {
+        if self.smol_sockets.contains_key(&smol_socket_handle) {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                format!("socket handle {} is already in use", smol_socket_handle),
+            ));
+        }
+        let options = options.or_defaults(&socket_type);
         match socket_type {
             SocketType::TCP => {
-                let rx_buffer = TcpSocketBuffer::new(vec![0; 65000]);
-                let tx_buffer = TcpSocketBuffer::new(vec![0; 65000]);
-                let socket = TcpSocket::new(rx_buffer, tx_buffer);
-                let handle = self.sockets.add(socket);
-                let smol_socket = SmolSocket::new(handle, SocketType::TCP, self.has_data.clone());
+                let handle = self.add_tcp_socket(&options);
+                let smol_socket =
+                    SmolSocket::new(handle, SocketType::TCP, self.has_data.clone(), options);
                 self.smol_sockets.insert(smol_socket_handle, smol_socket);
-                0
+                Ok(())
             }
             SocketType::UDP => {
-                let rx_buffer = UdpSocketBuffer::new(Vec::new(), vec![0; 1024]);
-                let tx_buffer = UdpSocketBuffer::new(Vec::new(), vec![0; 1024]);
+                let rx_buffer = UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; options.rx_packet_slots],
+                    vec![0; options.rx_buffer_size],
+                );
+                let tx_buffer = UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; options.tx_packet_slots],
+                    vec![0; options.tx_buffer_size],
+                );
                 let socket = UdpSocket::new(rx_buffer, tx_buffer);
                 let handle = self.sockets.add(socket);
-                let smol_socket = SmolSocket::new(handle, SocketType::UDP, self.has_data.clone());
+                let smol_socket =
+                    SmolSocket::new(handle, SocketType::UDP, self.has_data.clone(), options);
                 self.smol_sockets.insert(smol_socket_handle, smol_socket);
-                0
+                Ok(())
             }
-            /*
-            SocketType::RAW_IPV4 => {
-                let rx_buffer = RawSocketBuffer::new(Vec::new(), vec![0; 1024]);
-                let tx_buffer = RawSocketBuffer::new(Vec::new(), vec![0; 1024]);
-                //TODO: which protocol?
-                let socket = RawSocket::new(IpVersion::Ipv4,IpProtocol::Tcp,rx_buffer, tx_buffer);
-                self.sockets.add(socket);
+            SocketType::ICMP => {
+                let rx_buffer = IcmpSocketBuffer::new(
+                    vec![IcmpPacketMetadata::EMPTY; options.rx_packet_slots],
+                    vec![0; options.rx_buffer_size],
+                );
+                let tx_buffer = IcmpSocketBuffer::new(
+                    vec![IcmpPacketMetadata::EMPTY; options.tx_packet_slots],
+                    vec![0; options.tx_buffer_size],
+                );
+                let socket = IcmpSocket::new(rx_buffer, tx_buffer);
+                let handle = self.sockets.add(socket);
+                let smol_socket =
+                    SmolSocket::new(handle, SocketType::ICMP, self.has_data.clone(), options);
+                self.smol_sockets.insert(smol_socket_handle, smol_socket);
+                Ok(())
             }
+            SocketType::RAW_IPV4 | SocketType::RAW_IPV6 => Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "raw sockets need a protocol, use add_raw_socket",
+            )),
+        }
+    }
 
-            SocketType::RAW_IPV6 => {
-                let rx_buffer = RawSocketBuffer::new(Vec::new(), vec![0; 1024]);
-                let tx_buffer = RawSocketBuffer::new(Vec::new(), vec![0; 1024]);
-                //TODO: which protocol?
-                let socket = RawSocket::new(IpVersion::Ipv4,IpProtocol::Tcp,rx_buffer, tx_buffer);
-                self.sockets.add(socket);
-            }
-            */
+    /*
+        Raw sockets send and receive whole IP packets, header
+        included, of the given protocol. Options defaults are
+        the same as UDP's
+    */
+    pub fn add_raw_socket(
+        &mut self,
+        ip_version: IpVersion,
+        protocol: IpProtocol,
+        smol_socket_handle: usize,
+        options: CSocketOptions,
+    ) -> SmolResult<()> {
+        if self.smol_sockets.contains_key(&smol_socket_handle) {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                format!("socket handle {} is already in use", smol_socket_handle),
+            ));
+        }
+        let socket_type = match ip_version {
+            IpVersion::Ipv4 => SocketType::RAW_IPV4,
+            IpVersion::Ipv6 => SocketType::RAW_IPV6,
             _ => {
-                panic! {"wrong choice for socket type"}
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    "raw sockets are either IPv4 or IPv6",
+                ))
             }
-        }
+        };
+        let options = options.or_defaults(&socket_type);
+        let rx_buffer = RawSocketBuffer::new(
+            vec![PacketMetadata::EMPTY; options.rx_packet_slots],
+            vec![0; options.rx_buffer_size],
+        );
+        let tx_buffer = RawSocketBuffer::new(
+            vec![PacketMetadata::EMPTY; options.tx_packet_slots],
+            vec![0; options.tx_buffer_size],
+        );
+        let socket = RawSocket::new(ip_version, protocol, rx_buffer, tx_buffer);
+        let handle = self.sockets.add(socket);
+        let smol_socket = SmolSocket::new(handle, socket_type, self.has_data.clone(), options);
+        self.smol_sockets.insert(smol_socket_handle, smol_socket);
+        Ok(())
     }
 
     pub fn tcp_connect(
@@ -339,98 +977,385 @@ where
         address: CIpAddress,
         src_port: u16,
         dst_port: u16,
-    ) -> u8 {
-        let smol_socket_ = self.smol_sockets.get_mut(&smol_socket_handle);
-        match smol_socket_ {
-            Some(smol_socket) => {
-                let socket_handle = smol_socket.socket_handle;
-                let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
-                let endpoint_ = Into::<IpAddress>::into(address);
-                let endpoint: IpAddress = endpoint_.into();
-                println!("smol stack going to connect to {} with dst_port {} and src_port {}", endpoint, dst_port, src_port);
-                let r = socket.connect((endpoint_, dst_port), src_port);
-                smol_socket.endpoint = Some(endpoint);
-                let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                match r {
-                    Ok(_) => {
-                        //println!("connection ok");
-                        0
-                    }
-                    _ => {
-                        println!("connection error");
-                        2
-                    }
-                }
+    ) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        let socket_handle = smol_socket.socket_handle;
+        let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
+        let endpoint_ = Into::<IpAddress>::into(address);
+        let endpoint: IpAddress = endpoint_.into();
+        println!("smol stack going to connect to {} with dst_port {} and src_port {}", endpoint, dst_port, src_port);
+        let r = socket.connect((endpoint_, dst_port), src_port);
+        smol_socket.endpoint = Some(endpoint);
+        //Unlock the poller thread because new data is available
+        notify_all(self.has_data.as_ref().unwrap());
+        r.map_err(SmolError::from)
+    }
+
+    pub fn tcp_listen(&mut self, smol_socket_handle: usize, port: u16) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        let mut socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+        socket.listen(port)?;
+        smol_socket.listen_port = Some(port);
+        //Unlock the poller thread so the socket starts listening right away
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Returns the key of a SmolSocket for a connection that
+        arrived on the listening socket smol_socket_handle.
+        The returned socket is used as any other connected TCP socket
+    */
+    pub fn tcp_accept(&mut self, smol_socket_handle: usize) -> SmolResult<usize> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        smol_socket.accept().ok_or_else(|| {
+            SmolError::new(CSmolError::NoData, "no connection waiting to be accepted")
+        })
+    }
+
+    /*
+        smoltcp sockets handle one connection each, so when the listening
+        socket gets a connection, it's handed to a brand new SmolSocket
+        (queued for accept) and the listener gets a fresh TcpSocket that
+        listens on the same port. If listening again fails, the connection
+        is still queued for accept but the listener stops listening
+    */
+    fn rearm_listener(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        let (socket_handle, port, options) = match self.smol_sockets.get(&smol_socket_handle) {
+            Some(smol_socket) => match smol_socket.listen_port {
+                Some(port) => (smol_socket.socket_handle, port, smol_socket.options),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let remote_endpoint;
+        {
+            let socket = self.sockets.get::<TcpSocket>(socket_handle);
+            //Still waiting for a connection, or closed by the user
+            if !socket.is_active() {
+                return Ok(());
             }
-            None => {
-                panic!("NO smol socket");
-                1
+            remote_endpoint = socket.remote_endpoint();
+        }
+        let listener_socket_handle = self.add_tcp_socket(&options);
+        let listened = self.sockets.get::<TcpSocket>(listener_socket_handle).listen(port);
+        let accepted_key = self.new_socket_handle_key();
+        let mut accepted =
+            SmolSocket::new(socket_handle, SocketType::TCP, self.has_data.clone(), options);
+        accepted.endpoint = Some(remote_endpoint.addr);
+        self.smol_sockets.insert(accepted_key, accepted);
+
+        let listener = self.smol_sockets.get_mut(&smol_socket_handle).unwrap();
+        listener.socket_handle = listener_socket_handle;
+        listener.accepted.push_back(accepted_key);
+        notify_all(&listener.smol_socket_has_data);
+        if listened.is_err() {
+            listener.listen_port = None;
+        }
+        listened.map_err(SmolError::from)
+    }
+
+    /*
+        Graceful close. Packets already queued are still sent, then
+        TCP sockets send FIN. Listening sockets stop accepting connections
+    */
+    pub fn socket_close(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        let smol_socket = self.get_smol_socket(smol_socket_handle)?;
+        smol_socket.closing = true;
+        smol_socket.listen_port = None;
+        //What was already received can still be read, nothing more is
+        smol_socket.end_receive(CSmolError::SocketClosed);
+        //Unlock the poller thread so the close happens right away if nothing is pending
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Half close. Packets already queued are still sent, then FIN,
+        but received data keeps being delivered until the peer closes
+    */
+    pub fn tcp_shutdown_write(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        smol_socket.write_shutdown = true;
+        //Unlock the poller thread so FIN goes out right away if nothing is pending
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Closes immediately, dropping anything queued to be sent.
+        TCP sockets send RST
+    */
+    pub fn socket_abort(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        let smol_socket = self
+            .smol_sockets
+            .get_mut(&smol_socket_handle)
+            .ok_or_else(|| SmolError::socket_not_found(smol_socket_handle))?;
+        smol_socket.closing = true;
+        smol_socket.listen_port = None;
+        smol_socket.discard_pending_send();
+        smol_socket.end_receive(CSmolError::SocketClosed);
+        match smol_socket.socket_type {
+            SocketType::TCP => self.sockets.get::<TcpSocket>(smol_socket.socket_handle).abort(),
+            SocketType::UDP => self.sockets.get::<UdpSocket>(smol_socket.socket_handle).close(),
+            _ => {}
+        }
+        //Unlock the poller thread so the RST goes out
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Frees the SmolSocket and its smoltcp socket. Queued packets are
+        dropped, which calls the C++ destructors of their owners. Nothing
+        more is sent on the wire, so call close or abort first and let the
+        stack poll if the peer should be told. Removing a listener aborts
+        and removes the connections still waiting to be accepted
+    */
+    pub fn socket_remove(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        let mut smol_socket = self
+            .smol_sockets
+            .remove(&smol_socket_handle)
+            .ok_or_else(|| SmolError::socket_not_found(smol_socket_handle))?;
+        /*
+            Connections the listener got but nobody accepted have no
+            other owner that could remove them, so they go with it
+        */
+        let accepted: Vec<usize> = smol_socket.accepted.drain(..).collect();
+        for accepted_key in accepted {
+            if let Some(mut accepted) = self.smol_sockets.remove(&accepted_key) {
+                self.sockets.get::<TcpSocket>(accepted.socket_handle).abort();
+                self.drop_smol_socket(&mut accepted);
             }
         }
+        self.drop_smol_socket(&mut smol_socket);
+        Ok(())
     }
 
-    pub fn may_send(&mut self, smol_socket_handle: usize) -> u8 {
-        let smol_socket = self.smol_sockets.get_mut(&smol_socket_handle);
-        let socket_handle = smol_socket.as_ref().unwrap().socket_handle.clone();
-        let socket_type = &smol_socket.unwrap().socket_type;
+    fn drop_smol_socket(&mut self, smol_socket: &mut SmolSocket) {
+        smol_socket.discard_pending_send();
+        //Receivers still waiting on it return SocketNotFound
+        smol_socket.end_receive(CSmolError::SocketNotFound);
+        self.sockets.remove(smol_socket.socket_handle);
+    }
 
-        match socket_type {
+    pub fn tcp_set_option(&mut self, smol_socket_handle: usize, option: TcpOption) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        let mut socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+        let millis = |duration: Option<Duration>| {
+            duration.map(|duration| smoltcp::time::Duration::from_millis(duration.as_millis() as u64))
+        };
+        match option {
+            TcpOption::KeepAlive(interval) => socket.set_keep_alive(millis(interval)),
+            TcpOption::Timeout(timeout) => socket.set_timeout(millis(timeout)),
+            //This smoltcp sends segments as soon as it can, so Nagle is always off
+            TcpOption::Nagle(false) => {}
+            TcpOption::Nagle(true) => {
+                return Err(SmolError::new(
+                    CSmolError::NotSupported,
+                    "Nagle's algorithm isn't implemented by smoltcp".to_owned(),
+                ))
+            }
+            //smoltcp panics on a hop limit of 0
+            TcpOption::HopLimit(Some(0)) => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    "hop limit can't be 0".to_owned(),
+                ))
+            }
+            TcpOption::HopLimit(hop_limit) => socket.set_hop_limit(hop_limit),
+        }
+        //The poller has to send keep-alives and check the timeout
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    //Diagnostics for a TCP connection
+    pub fn tcp_info(&mut self, smol_socket_handle: usize) -> SmolResult<CTcpInfo> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        let socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+        let specified = |endpoint: IpEndpoint| {
+            if endpoint.is_specified() {
+                Some(endpoint)
+            } else {
+                None
+            }
+        };
+        let to_send_len = smol_socket.to_send.lock().unwrap().len()
+            + smol_socket.current_to_send.is_some() as usize;
+        Ok(CTcpInfo {
+            state: socket.state().into(),
+            local_endpoint: CIpEndpoint::from(specified(socket.local_endpoint())),
+            remote_endpoint: CIpEndpoint::from(specified(socket.remote_endpoint())),
+            tx_queued_bytes: socket.send_queue(),
+            rx_queued_bytes: socket.recv_queue(),
+            to_send_len: to_send_len,
+            received_len: smol_socket.received.lock().unwrap().len(),
+            rtt_millis: smol_socket.rtt.millis(),
+        })
+    }
+
+    pub fn may_send(&mut self, smol_socket_handle: usize) -> SmolResult<bool> {
+        let smol_socket = self
+            .smol_sockets
+            .get(&smol_socket_handle)
+            .ok_or_else(|| SmolError::socket_not_found(smol_socket_handle))?;
+        let socket_handle = smol_socket.socket_handle.clone();
+
+        match smol_socket.socket_type {
             SocketType::TCP => {
                 let socket = self.sockets.get::<TcpSocket>(socket_handle.clone());
-                if socket.may_send() {
-                    0
-                } else {
-                    1
-                }
+                Ok(socket.may_send())
             },
             SocketType::UDP => {
                 let socket = self.sockets.get::<UdpSocket>(socket_handle.clone());
-                panic!("not implemented yet");
+                Ok(socket.is_open() && socket.can_send())
             }
-            _ => {
-                panic!("not implemented yet");
+            SocketType::ICMP => {
+                let socket = self.sockets.get::<IcmpSocket>(socket_handle.clone());
+                Ok(socket.is_open() && socket.can_send())
+            }
+            SocketType::RAW_IPV4 | SocketType::RAW_IPV6 => {
+                let socket = self.sockets.get::<RawSocket>(socket_handle.clone());
+                Ok(socket.can_send())
             }
         }
     }
 
-    //deprecated
-    pub fn tcp_connect_ipv4(
-        &mut self,
-        smol_socket_handle: usize,
-        address: CIpv4Address,
-        src_port: u16,
-        dst_port: u16,
-    ) -> u8 {
-        let smol_socket_ = self.smol_sockets.get_mut(&smol_socket_handle);
-        match smol_socket_ {
-            Some(smol_socket) => {
-                let socket_handle = smol_socket.socket_handle;
-                let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
-                let endpoint_ = Into::<IpAddress>::into(address);
-                let endpoint: IpAddress = endpoint_.into();
-                let r = socket.connect((endpoint_, dst_port), src_port);
-                smol_socket.endpoint = Some(endpoint);
-                let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                match r {
-                    Ok(_) => {
-                        //println!("connection ok");
-                        0
-                    }
-                    _ => {
-                        println!("connection error");
-                        2
-                    }
-                }
+    /*
+        Binds the UDP socket to a local port. Port 0 picks
+        a random ephemeral port. A UDP socket must be bound
+        before it can send or receive
+    */
+    pub fn udp_bind(&mut self, smol_socket_handle: usize, port: u16) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::UDP)?;
+        let port = if port == 0 {
+            rand::thread_rng().gen_range(49152, 65535)
+        } else {
+            port
+        };
+        let mut socket = self.sockets.get::<UdpSocket>(smol_socket.socket_handle);
+        socket.bind(port)?;
+        //Unlock the poller thread so queued datagrams can go out
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Binds the ICMP socket to an identifier. Only echo replies
+        carrying it are received. Must be done before sending
+    */
+    pub fn icmp_bind(&mut self, smol_socket_handle: usize, ident: u16) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::ICMP)?;
+        let mut socket = self.sockets.get::<IcmpSocket>(smol_socket.socket_handle);
+        socket.bind(IcmpEndpoint::Ident(ident))?;
+        smol_socket.icmp_ident = Some(ident);
+        Ok(())
+    }
+
+    /*
+        Sends an echo request to address right away. The send time goes
+        in front of data, so the reply tells the round trip time
+    */
+    pub fn icmp_send_echo(
+        &mut self,
+        smol_socket_handle: usize,
+        address: IpAddress,
+        seq_no: u16,
+        data: &[u8],
+    ) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::ICMP)?;
+        if smol_socket.closing {
+            return Err(SmolError::new(CSmolError::SocketClosed, "socket was closed"));
+        }
+        let ident = smol_socket.icmp_ident.ok_or_else(|| {
+            SmolError::new(
+                CSmolError::InvalidArgument,
+                "ICMP socket must be bound before sending",
+            )
+        })?;
+        let interface = self.interface.as_ref().ok_or_else(|| {
+            SmolError::new(CSmolError::NotFinalized, "stack is not finalized yet")
+        })?;
+        let checksum = interface.device().capabilities().checksum;
+        let mut payload = Instant::now().total_millis().to_be_bytes().to_vec();
+        payload.extend_from_slice(data);
+        let mut socket = self.sockets.get::<IcmpSocket>(smol_socket.socket_handle);
+        match address {
+            IpAddress::Ipv4(_) => {
+                let icmp_repr = Icmpv4Repr::EchoRequest {
+                    ident: ident,
+                    seq_no: seq_no,
+                    data: &payload,
+                };
+                let buffer = socket.send(icmp_repr.buffer_len(), address)?;
+                let mut packet = Icmpv4Packet::new_unchecked(buffer);
+                icmp_repr.emit(&mut packet, &checksum);
             }
-            None => {
-                panic!("NO smol socket");
-                1
+            IpAddress::Ipv6(_) => {
+                //The ICMPv6 checksum covers the source address
+                let src_address = interface
+                    .ip_addrs()
+                    .iter()
+                    .map(|cidr| cidr.address())
+                    .find(|address| match address {
+                        &IpAddress::Ipv6(_) => true,
+                        _ => false,
+                    })
+                    .ok_or_else(|| {
+                        SmolError::new(
+                            CSmolError::InvalidArgument,
+                            "the stack has no IPv6 address to ping from",
+                        )
+                    })?;
+                let icmp_repr = Icmpv6Repr::EchoRequest {
+                    ident: ident,
+                    seq_no: seq_no,
+                    data: &payload,
+                };
+                let buffer = socket.send(icmp_repr.buffer_len(), address)?;
+                let mut packet = Icmpv6Packet::new_unchecked(buffer);
+                icmp_repr.emit(&src_address, &address, &mut packet, &checksum);
+            }
+            _ => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    "echo requests need an IPv4 or IPv6 address",
+                ))
             }
         }
+        //Unlock the poller thread so the request goes out
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    //Oldest echo reply received on the ICMP socket
+    pub fn icmp_receive_echo(&mut self, smol_socket_handle: usize) -> SmolResult<EchoReply> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::ICMP)?;
+        smol_socket
+            .echo_replies
+            .pop_front()
+            .ok_or_else(|| SmolError::new(CSmolError::NoData, "no echo reply received"))
+    }
+
+    //deprecated
+    pub fn tcp_connect_ipv4(
+        &mut self,
+        smol_socket_handle: usize,
+        address: CIpv4Address,
+        src_port: u16,
+        dst_port: u16,
+    ) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        let socket_handle = smol_socket.socket_handle;
+        let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
+        let endpoint_ = Into::<IpAddress>::into(address);
+        let endpoint: IpAddress = endpoint_.into();
+        let r = socket.connect((endpoint_, dst_port), src_port);
+        smol_socket.endpoint = Some(endpoint);
+        //Unlock the poller thread because new data is available
+        notify_all(self.has_data.as_ref().unwrap());
+        r.map_err(SmolError::from)
     }
 
     //deprecated
@@ -440,195 +1365,957 @@ where
         address: CIpv6Address,
         src_port: u16,
         dst_port: u16,
-    ) -> u8 {
-        let smol_socket_ = self.smol_sockets.get(&smol_socket_handle);
-        match smol_socket_ {
-            Some(smol_socket) => {
-                let socket_handle = smol_socket.socket_handle;
-                let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
-                let r = socket.connect((Into::<Ipv6Address>::into(address), dst_port), src_port);
-                let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                match r {
-                    Ok(_) => 0,
-                    _ => 2,
-                }
-            }
-            None => 1,
-        }
+    ) -> SmolResult<()> {
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        let socket_handle = smol_socket.socket_handle;
+        let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
+        let r = socket.connect((Into::<Ipv6Address>::into(address), dst_port), src_port);
+        //Unlock the poller thread because new data is available
+        notify_all(self.has_data.as_ref().unwrap());
+        r.map_err(SmolError::from)
+    }
+
+    fn staged_ip_addrs(&mut self) -> SmolResult<&mut Vec<IpCidr>> {
+        self.ip_addrs.as_mut().ok_or_else(|| {
+            SmolError::new(
+                CSmolError::AlreadyFinalized,
+                "addresses can't be added after finalize",
+            )
+        })
     }
 
-    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) {
-        self.ip_addrs.as_mut().unwrap().push(IpCidr::new(
+    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) -> SmolResult<()> {
+        self.staged_ip_addrs()?.push(IpCidr::new(
             Into::<IpAddress>::into(cidr.address),
             cidr.prefix,
         ));
+        Ok(())
     }
 
-    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) {
-        self.ip_addrs.as_mut().unwrap().push(IpCidr::new(
+    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) -> SmolResult<()> {
+        self.staged_ip_addrs()?.push(IpCidr::new(
             Into::<IpAddress>::into(cidr.address),
             cidr.prefix,
         ));
+        Ok(())
     }
 
-    pub fn add_default_v4_gateway(&mut self, address: CIpv4Address) {
-        self.default_v4_gw = Some(Into::<Ipv4Address>::into(address));
+    /*
+        Replaces every address of the stack. Before finalize that's
+        the staged addresses, after it the live Interface's, so
+        existing sockets are kept
+    */
+    pub fn set_addresses(&mut self, ip_addrs: Vec<IpCidr>) -> SmolResult<()> {
+        //Interface panics on these, which would poison the stack
+        for cidr in ip_addrs.iter() {
+            if !cidr.address().is_unicast() {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("{} is not a unicast address", cidr),
+                ));
+            }
+        }
+        match self.interface.as_mut() {
+            Some(interface) => {
+                interface.update_ip_addrs(move |addrs| *addrs = ip_addrs.into());
+                //Unlock the poller thread so the new addresses are used right away
+                notify_all(self.has_data.as_ref().unwrap());
+            }
+            None => self.ip_addrs = Some(ip_addrs),
+        }
+        Ok(())
     }
 
-    pub fn add_default_v6_gateway(&mut self, address: CIpv6Address) {
-        self.default_v6_gw = Some(Into::<Ipv6Address>::into(address));
+    /*
+        Adds or replaces the route to cidr, through the gateway via_router.
+        Before finalize the route is staged, after it the live Interface
+        gets it
+    */
+    pub fn add_route(&mut self, cidr: IpCidr, via_router: IpAddress) -> SmolResult<()> {
+        let route = match (cidr.address(), via_router) {
+            (IpAddress::Ipv4(_), IpAddress::Ipv4(gateway)) => Route::new_ipv4_gateway(gateway),
+            (IpAddress::Ipv6(_), IpAddress::Ipv6(gateway)) => Route::new_ipv6_gateway(gateway),
+            _ => {
+                return Err(SmolError::new(
+                    CSmolError::InvalidArgument,
+                    format!("{} can't be the gateway for {}", via_router, cidr),
+                ))
+            }
+        };
+        match self.interface.as_mut() {
+            Some(interface) => {
+                let mut inserted = Ok(None);
+                interface
+                    .routes_mut()
+                    .update(|routes| inserted = routes.insert(cidr, route));
+                inserted.map_err(|_| SmolError::from(smoltcp::Error::Exhausted))?;
+                notify_all(self.has_data.as_ref().unwrap());
+            }
+            None => {
+                self.routes.as_mut().unwrap().insert(cidr, route);
+            }
+        }
+        Ok(())
     }
 
-    pub fn finalize(&mut self) -> u8 {
-        let routes_storage = BTreeMap::new();
-        let mut routes = Routes::new(routes_storage);
-        //TODO: return C error if something is wrong, no unwrap
-        routes
-            .add_default_ipv4_route(self.default_v4_gw.unwrap())
-            .unwrap();
-        routes
-            .add_default_ipv6_route(self.default_v6_gw.unwrap())
-            .unwrap();
-        let interface = InterfaceBuilder::new(self.device.take().unwrap())
+    //cidr must be exactly the one the route was added with
+    pub fn remove_route(&mut self, cidr: IpCidr) -> SmolResult<()> {
+        let removed = match self.interface.as_mut() {
+            Some(interface) => {
+                let mut removed = None;
+                interface
+                    .routes_mut()
+                    .update(|routes| removed = routes.remove(&cidr));
+                //Unlock the poller thread, poll_delay may change without the route
+                notify_all(self.has_data.as_ref().unwrap());
+                removed
+            }
+            None => self.routes.as_mut().unwrap().remove(&cidr),
+        };
+        removed.map(|_| ()).ok_or_else(|| {
+            SmolError::new(
+                CSmolError::InvalidArgument,
+                format!("there's no route to {}", cidr),
+            )
+        })
+    }
+
+    //Same as a route to 0.0.0.0/0
+    pub fn add_default_v4_gateway(&mut self, address: CIpv4Address) -> SmolResult<()> {
+        let default_cidr = IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0);
+        self.add_route(default_cidr, Into::<IpAddress>::into(address))
+    }
+
+    //Same as a route to ::/0
+    pub fn add_default_v6_gateway(&mut self, address: CIpv6Address) -> SmolResult<()> {
+        let default_cidr = IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0), 0);
+        self.add_route(default_cidr, Into::<IpAddress>::into(address))
+    }
+
+    pub fn finalize(&mut self) -> SmolResult<()> {
+        if self.device.is_none() {
+            return Err(SmolError::new(
+                CSmolError::AlreadyFinalized,
+                "stack was already finalized",
+            ));
+        }
+        let medium = self.device.as_ref().unwrap().medium();
+        if medium == Medium::Ethernet && self.ethernet_addr.is_none() {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "Ethernet devices need a MAC address",
+            ));
+        }
+        //No default gateway for a family means only its local networks are reachable
+        let routes = Routes::new(self.routes.take().unwrap());
+        let mut interface_builder = InterfaceBuilder::new(self.device.take().unwrap())
             .ip_addrs(self.ip_addrs.take().unwrap())
-            .routes(routes)
-            .finalize();
+            .routes(routes);
+        let neighbors = self.neighbors.take().unwrap();
+        if medium == Medium::Ethernet {
+            let mut neighbor_cache = NeighborCache::new(BTreeMap::new());
+            let timestamp = Instant::now();
+            for (ip_address, ethernet_address) in neighbors {
+                neighbor_cache.fill(ip_address, ethernet_address, timestamp);
+            }
+            interface_builder = interface_builder
+                .ethernet_addr(self.ethernet_addr.unwrap())
+                .neighbor_cache(neighbor_cache);
+        }
+        let interface = interface_builder.finalize();
         self.interface = Some(interface);
-        0
+        Ok(())
     }
 
-    pub fn poll(&mut self) -> u8 {
-        let timestamp = Instant::now();
-        match self
-            .interface
+    pub fn set_ethernet_address(&mut self, address: EthernetAddress) -> SmolResult<()> {
+        if self.device.is_none() {
+            return Err(SmolError::new(
+                CSmolError::AlreadyFinalized,
+                "the MAC address can't be changed after finalize",
+            ));
+        }
+        //smoltcp panics on finalize otherwise
+        if !address.is_unicast() {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                format!("{} is not a unicast MAC address", address),
+            ));
+        }
+        self.ethernet_addr = Some(address);
+        Ok(())
+    }
+
+    /*
+        Seeds the neighbour cache, so the first packets to address
+        don't wait for ARP/NDP. Entries expire like learned ones
+    */
+    pub fn add_neighbor(&mut self, address: IpAddress, ethernet_address: EthernetAddress) -> SmolResult<()> {
+        self.neighbors
             .as_mut()
-            .unwrap()
-            .poll(&mut self.sockets, timestamp)
-        {
-            Ok(_) => 0,
+            .ok_or_else(|| {
+                SmolError::new(
+                    CSmolError::AlreadyFinalized,
+                    "neighbours can't be added after finalize",
+                )
+            })?
+            .push((address, ethernet_address));
+        Ok(())
+    }
+
+    pub fn poll(&mut self) -> SmolResult<()> {
+        let timestamp = Instant::now();
+        /*
+            Whatever notifies from now on (send, connect, close...) may
+            not be seen by this poll or the spin_all after it
+        */
+        if let Some(has_data) = self.has_data.as_ref() {
+            self.polled_notifications = *has_data.0.lock().unwrap();
+        }
+        let interface = self.interface.as_mut().ok_or_else(|| {
+            SmolError::new(CSmolError::NotFinalized, "stack is not finalized yet")
+        })?;
+        let result = match interface.poll(&mut self.sockets, timestamp) {
+            Ok(_) => Ok(()),
             Err(e) => {
                 //debug!("poll error: {}",e);
-                1
+                Err(e.into())
             }
+        };
+        //DHCP and DNS run even if the interface failed, the first error is returned
+        let dhcp_result = self.poll_dhcp(timestamp);
+        let dns_result = self.poll_dns(timestamp);
+        result.and(dhcp_result).and(dns_result)
+    }
+
+    /*
+        Starts a DHCPv4 client on an Ethernet stack (Tap, VirtualTap).
+        poll drives it once the stack is finalized: the leased address
+        replaces the IPv4 addresses, the router becomes the default IPv4
+        gateway (both are taken away when the lease is lost), and callback
+        (if any) gets every new lease
+    */
+    pub fn enable_dhcpv4(
+        &mut self,
+        callback: Option<DhcpLeaseCallback>,
+        context: *mut c_void,
+    ) -> SmolResult<()> {
+        if self.dhcp.is_some() {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "DHCP is already enabled",
+            ));
+        }
+        let medium = match (&self.device, &self.interface) {
+            (Some(device), _) => device.medium(),
+            (None, Some(interface)) => interface.device().medium(),
+            (None, None) => return Err(SmolError::new(CSmolError::Device, "stack has no device")),
+        };
+        if medium != Medium::Ethernet {
+            return Err(SmolError::new(
+                CSmolError::NotSupported,
+                "DHCP needs an Ethernet stack (Tap or VirtualTap)",
+            ));
+        }
+        //Same sizes smoltcp's DHCP example uses
+        let rx_buffer = RawSocketBuffer::new(vec![PacketMetadata::EMPTY; 1], vec![0; 900]);
+        let tx_buffer = RawSocketBuffer::new(vec![PacketMetadata::EMPTY; 1], vec![0; 600]);
+        let client = Dhcpv4Client::new(&mut self.sockets, rx_buffer, tx_buffer, Instant::now());
+        self.dhcp = Some(Dhcp {
+            client: client,
+            callback: callback,
+            context: context,
+            lease: None,
+        });
+        //Unlock the poller thread so discovery starts right away
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    //Used by dns_resolve, together with the ones DHCP hands out
+    pub fn add_dns_server(&mut self, address: IpAddress) -> SmolResult<()> {
+        if !address.is_unicast() {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                format!("{} can't be a DNS server", address),
+            ));
+        }
+        if !self.dns_servers.contains(&address) {
+            self.dns_servers.push(address);
+        }
+        Ok(())
+    }
+
+    fn nameservers(&self) -> Vec<IpAddress> {
+        let mut nameservers = self.dns_servers.clone();
+        if let Some(lease) = self.dhcp.as_ref().and_then(|dhcp| dhcp.lease) {
+            for dns_server in lease.dns_servers.iter().filter_map(|dns_server| *dns_server) {
+                let address = IpAddress::Ipv4(dns_server);
+                if !nameservers.contains(&address) {
+                    nameservers.push(address);
+                }
+            }
+        }
+        nameservers
+    }
+
+    fn dns_socket(&mut self) -> SmolResult<SocketHandle> {
+        if let Some(socket_handle) = self.dns_socket {
+            return Ok(socket_handle);
+        }
+        let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 16], vec![0; 8192]);
+        let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 16], vec![0; 8192]);
+        let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+        socket.bind(rand::thread_rng().gen_range(49152, 65535))?;
+        let socket_handle = self.sockets.add(socket);
+        self.dns_socket = Some(socket_handle);
+        Ok(socket_handle)
+    }
+
+    /*
+        Looks up the A and AAAA records of name. poll sends the queries,
+        retries them on other servers and eventually calls callback with
+        Ok and the addresses, NoData if there are none or Timeout
+    */
+    pub fn dns_resolve(
+        &mut self,
+        name: &str,
+        callback: DnsResolveCallback,
+        context: *mut c_void,
+    ) -> SmolResult<()> {
+        if self.nameservers().is_empty() {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                "no DNS servers, add one or enable DHCP",
+            ));
         }
+        //Only to check the name, poll builds the queries it sends
+        dns::build_query(0, name, dns::TYPE_A)?;
+        self.dns_socket()?;
+        let mut rng = rand::thread_rng();
+        self.dns_queries.push(DnsQuery {
+            name: name.to_owned(),
+            pending: vec![(rng.gen(), dns::TYPE_A), (rng.gen(), dns::TYPE_AAAA)],
+            addresses: Vec::new(),
+            name_error: false,
+            attempts: 0,
+            next_send: Instant::now(),
+            callback: callback,
+            context: context,
+        });
+        //Unlock the poller thread so the queries go out
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
     }
 
-    pub fn spin_all(&mut self) -> u8 {
+    fn poll_dns(&mut self, timestamp: Instant) -> SmolResult<()> {
+        let socket_handle = match self.dns_socket {
+            Some(socket_handle) => socket_handle,
+            None => return Ok(()),
+        };
+        let nameservers = self.nameservers();
+        let mut finished = Vec::new();
+        //A query that couldn't be sent is still retried, and fails through its callback in the end
+        let mut result = Ok(());
+        {
+            let mut socket = self.sockets.get::<UdpSocket>(socket_handle);
+            while socket.can_recv() {
+                let (data, endpoint) = match socket.recv() {
+                    Ok(received) => received,
+                    Err(_) => break,
+                };
+                if endpoint.port != dns::DNS_PORT || !nameservers.contains(&endpoint.addr) {
+                    continue;
+                }
+                let response = match dns::parse_response(data) {
+                    Some(response) => response,
+                    None => continue,
+                };
+                for query in self.dns_queries.iter_mut() {
+                    //The question has to be echoed too, an id alone is easy to guess
+                    if query.name.trim_end_matches('.').to_lowercase() != response.name {
+                        continue;
+                    }
+                    let answers = |&(id, query_type): &(u16, u16)| {
+                        id == response.id && query_type == response.query_type
+                    };
+                    if let Some(i) = query.pending.iter().position(answers) {
+                        query.pending.remove(i);
+                        query.addresses.extend(response.addresses.iter().cloned());
+                        //NXDOMAIN
+                        if response.rcode == 3 {
+                            query.name_error = true;
+                        }
+                        break;
+                    }
+                }
+            }
+            let mut i = 0;
+            while i < self.dns_queries.len() {
+                let done = {
+                    let query = &self.dns_queries[i];
+                    query.pending.is_empty()
+                        || nameservers.is_empty()
+                        || (timestamp >= query.next_send && query.attempts == dns::MAX_ATTEMPTS)
+                };
+                if done {
+                    finished.push(self.dns_queries.remove(i));
+                    continue;
+                }
+                let query = &mut self.dns_queries[i];
+                if timestamp >= query.next_send {
+                    //Each retry goes to the next server
+                    let server = nameservers[query.attempts % nameservers.len()];
+                    for &(id, query_type) in query.pending.iter() {
+                        //The name was checked by dns_resolve
+                        if let Ok(packet) = dns::build_query(id, &query.name, query_type) {
+                            if let Err(e) = socket.send_slice(&packet, IpEndpoint::new(server, dns::DNS_PORT)) {
+                                if result.is_ok() {
+                                    result = Err(SmolError::new(
+                                        CSmolError::Smoltcp,
+                                        format!("DNS query not sent: {}", e),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    query.attempts += 1;
+                    query.next_send =
+                        timestamp + smoltcp::time::Duration::from_millis(dns::RETRY_INTERVAL_MILLIS);
+                }
+                i += 1;
+            }
+        }
+        for query in finished {
+            self.events.push(query.finish());
+        }
+        result
+    }
+
+    fn poll_dhcp(&mut self, timestamp: Instant) -> SmolResult<()> {
+        let (dhcp, interface) = match (self.dhcp.as_mut(), self.interface.as_mut()) {
+            (Some(dhcp), Some(interface)) => (dhcp, interface),
+            _ => return Ok(()),
+        };
+        let config = match dhcp.client.poll(interface, &mut self.sockets, timestamp) {
+            Ok(Some(config)) => config,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                return Err(SmolError::new(CSmolError::Smoltcp, format!("DHCP error: {}", e)));
+            }
+        };
+        let lease = DhcpLease {
+            address: config.address,
+            router: config.router,
+            dns_servers: config.dns_servers,
+        };
+        //Renewals give the same lease again
+        if dhcp.lease == Some(lease) {
+            return Ok(());
+        }
+        let previous = dhcp.lease;
+        let previous_address = previous.and_then(|previous| previous.address);
+        if lease.address != previous_address {
+            interface.update_ip_addrs(|addrs| {
+                let mut ip_addrs: Vec<IpCidr> = addrs
+                    .iter()
+                    .filter(|cidr| match cidr {
+                        //A new address replaces every IPv4 one, a lost lease only takes its own
+                        &&IpCidr::Ipv4(ipv4_cidr) => {
+                            lease.address.is_none() && Some(ipv4_cidr) != previous_address
+                        }
+                        _ => true,
+                    })
+                    .cloned()
+                    .collect();
+                if let Some(cidr) = lease.address {
+                    ip_addrs.push(IpCidr::Ipv4(cidr));
+                }
+                *addrs = ip_addrs.into();
+            });
+        }
+        //The router is only installed along with an address
+        let previous_router = previous.and_then(|previous| previous.address.and(previous.router));
+        let router = lease.address.and(lease.router);
+        //The lease is kept (and handed to the callback) even if its router couldn't be
+        let mut result = Ok(());
+        if router != previous_router {
+            let default_cidr = IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0);
+            let mut inserted = Ok(None);
+            interface.routes_mut().update(|routes| {
+                //Only a default route an earlier lease installed, not a static one
+                if previous_router.is_some() {
+                    routes.remove(&default_cidr);
+                }
+                if let Some(router) = router {
+                    inserted = routes.insert(default_cidr, Route::new_ipv4_gateway(router));
+                }
+            });
+            if let Err(e) = inserted {
+                result = Err(SmolError::new(
+                    CSmolError::Smoltcp,
+                    format!("couldn't add the DHCP router as default gateway: {}", e),
+                ));
+            }
+        }
+        dhcp.lease = Some(lease);
+        if let Some(callback) = dhcp.callback {
+            self.events.push(Event::DhcpLease(callback, CDhcpLease::from(lease), dhcp.context));
+        }
+        result
+    }
+
+    /*
+        Spins every socket, even if some of them fail.
+        Returns the first error
+    */
+    pub fn spin_all(&mut self) -> SmolResult<()> {
         //TODO: maybe store self.smol_sockets in a smart pointer
         //so we don't do this copy every time
         let mut smol_socket_handles = Vec::<usize>::new();
         for (smol_socket_handle, smol_socket) in self.smol_sockets.iter_mut() {
             smol_socket_handles.push(smol_socket_handle.clone());
         }
+        let mut result = Ok(());
         for (smol_socket_handle) in smol_socket_handles.iter_mut() {
-            self.spin(smol_socket_handle.clone());
+            let r = self.spin(smol_socket_handle.clone());
+            if result.is_ok() {
+                result = r;
+            }
         }
-        0
+        result
     }
 
     /*
         Sends/receives packets queued in the given SmolSocket/socket
-        pointed by smol_socket_handle
+        pointed by smol_socket_handle, then queues its callbacks for
+        whatever changed
+    */
+    pub fn spin(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        let result = self.spin_socket(smol_socket_handle);
+        self.queue_callbacks(smol_socket_handle, &result);
+        result
+    }
+
+    /*
+        Callbacks queued by spin and poll. The caller fires them
+        once it released the stack
     */
-    pub fn spin(&mut self, smol_socket_handle: usize) -> u8 {
-        let smol_socket = self.smol_sockets.get_mut(&smol_socket_handle).unwrap();
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /*
+        Callbacks for the socket, or None to remove them. Events that
+        already happened (a connection that's established, data waiting)
+        are reported on the next spin
+    */
+    pub fn set_socket_callbacks(
+        &mut self,
+        smol_socket_handle: usize,
+        callbacks: Option<CSocketCallbacks>,
+    ) -> SmolResult<()> {
+        let smol_socket = self.get_smol_socket(smol_socket_handle)?;
+        smol_socket.callbacks = callbacks;
+        smol_socket.event_state = EventState::new();
+        //Unlock the poller thread so pending events are reported right away
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Edge triggered: readable is queued when the socket goes from
+        nothing to receive (or accept) to something, not for every packet
+    */
+    fn queue_callbacks(&mut self, smol_socket_handle: usize, result: &SmolResult<()>) {
+        let smol_socket = match self.smol_sockets.get_mut(&smol_socket_handle) {
+            Some(smol_socket) => smol_socket,
+            None => return,
+        };
+        let callbacks = match smol_socket.callbacks {
+            Some(callbacks) => callbacks,
+            None => return,
+        };
+        let queued = !smol_socket.received.lock().unwrap().is_empty()
+            || !smol_socket.accepted.is_empty()
+            || !smol_socket.echo_replies.is_empty();
+        let previous = smol_socket.event_state;
+        let current = match smol_socket.socket_type {
+            SocketType::TCP => {
+                let socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+                EventState {
+                    tcp_state: socket.state(),
+                    //End of stream is readable too, receive reports it
+                    readable: queued
+                        || (smol_socket.direct_receive && socket.can_recv())
+                        || *smol_socket.ended.lock().unwrap() == Some(CSmolError::EndOfStream),
+                    writable: socket.may_send() && socket.can_send(),
+                }
+            }
+            SocketType::UDP => {
+                let socket = self.sockets.get::<UdpSocket>(smol_socket.socket_handle);
+                EventState {
+                    tcp_state: TcpState::Closed,
+                    readable: queued,
+                    writable: socket.is_open() && socket.can_send(),
+                }
+            }
+            SocketType::ICMP => {
+                let socket = self.sockets.get::<IcmpSocket>(smol_socket.socket_handle);
+                EventState {
+                    tcp_state: TcpState::Closed,
+                    readable: queued,
+                    writable: socket.is_open() && socket.can_send(),
+                }
+            }
+            SocketType::RAW_IPV4 | SocketType::RAW_IPV6 => {
+                let socket = self.sockets.get::<RawSocket>(smol_socket.socket_handle);
+                EventState {
+                    tcp_state: TcpState::Closed,
+                    readable: queued,
+                    writable: socket.can_send(),
+                }
+            }
+        };
+        smol_socket.event_state = current;
+        let context = callbacks.context;
+        if current.tcp_state != previous.tcp_state {
+            match (previous.tcp_state, current.tcp_state) {
+                (TcpState::Closed, TcpState::Established)
+                | (TcpState::Listen, TcpState::Established)
+                | (TcpState::SynSent, TcpState::Established)
+                | (TcpState::SynReceived, TcpState::Established) => {
+                    if let Some(on_connected) = callbacks.on_connected {
+                        self.events.push(Event::Socket(on_connected, smol_socket_handle, context));
+                    }
+                }
+                //Reset by the peer or timed out, without us closing it
+                (TcpState::SynSent, TcpState::Closed)
+                | (TcpState::SynReceived, TcpState::Closed)
+                | (TcpState::Established, TcpState::Closed)
+                | (TcpState::FinWait1, TcpState::Closed)
+                | (TcpState::FinWait2, TcpState::Closed)
+                | (TcpState::CloseWait, TcpState::Closed)
+                | (TcpState::Closing, TcpState::Closed)
+                    if !smol_socket.closing =>
+                {
+                    if let Some(on_error) = callbacks.on_error {
+                        self.events.push(Event::SocketError(
+                            on_error,
+                            smol_socket_handle,
+                            CSmolError::ConnectionReset,
+                            context,
+                        ));
+                    }
+                }
+                (_, TcpState::Closed) | (_, TcpState::TimeWait) => {
+                    if let Some(on_closed) = callbacks.on_closed {
+                        self.events.push(Event::Socket(on_closed, smol_socket_handle, context));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if current.readable && !previous.readable {
+            if let Some(on_readable) = callbacks.on_readable {
+                self.events.push(Event::Socket(on_readable, smol_socket_handle, context));
+            }
+        }
+        if current.writable && !previous.writable {
+            if let Some(on_writable) = callbacks.on_writable {
+                self.events.push(Event::Socket(on_writable, smol_socket_handle, context));
+            }
+        }
+        if let &Err(ref e) = result {
+            if let Some(on_error) = callbacks.on_error {
+                self.events.push(Event::SocketError(on_error, smol_socket_handle, e.code, context));
+            }
+        }
+    }
+
+    fn spin_socket(&mut self, smol_socket_handle: usize) -> SmolResult<()> {
+        self.rearm_listener(smol_socket_handle)?;
+        let smol_socket = self
+            .smol_sockets
+            .get_mut(&smol_socket_handle)
+            .ok_or_else(|| SmolError::socket_not_found(smol_socket_handle))?;
         match smol_socket.socket_type {
             SocketType::TCP => {
                 let mut socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
-                let mut put_back = false;
-                if socket.may_send() {
+                let now = Instant::now();
+                if socket.is_active() {
+                    smol_socket.rtt.acked(socket.send_queue(), now);
+                } else {
+                    smol_socket.rtt.stop();
+                }
+                //Packets in order until the tx buffer is full
+                while socket.may_send() && socket.can_send() {
                     //Returns None if there are no packets
-                    let mut packet = smol_socket.get_latest_packet();
-                    match &mut packet {
-                        Some(ref mut packet) => {
-                            //Sends from the start (which might be more than 0 if we didn't send
-                            //an entire packet in the last call)
-                            let bytes_sent = socket
-                                .send_slice(&packet.blob.data.as_slice()[packet.blob.start..]);
-                            match bytes_sent {
-                                Ok(bytes_sent) => {
-                                    /*
-                                        Sent less than entire packet, so we must put this packet
-                                        in `smol_socket.current_to_send` so it's returned the next time
-                                        so we can continue sending it
-                                    */
-                                    if bytes_sent < packet.blob.data.len() {
-                                        let remaining_bytes = packet.blob.data.len() - bytes_sent;
-                                        //start from remaining in the next call
-                                        packet.blob.start = remaining_bytes;
-                                        put_back = true;
-                                    } else {
-                                        //Sent the entire packet, nothing needs to be done
-                                        //0
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("bytes not sent, ERROR {}, putting packet back", e);
-                                    //1
-                                }
+                    let mut packet = match smol_socket.get_latest_packet() {
+                        Some(packet) => packet,
+                        None => break,
+                    };
+                    let idle = socket.send_queue() == 0;
+                    //Sends from the start (which might be more than 0 if we didn't send
+                    //an entire packet in the last call)
+                    match socket.send_slice(&packet.blob.data.as_slice()[packet.blob.start..]) {
+                        Ok(bytes_sent) => {
+                            smol_socket.rtt.sent(bytes_sent, idle, now);
+                            packet.blob.start += bytes_sent;
+                            /*
+                                Sent less than entire packet, so we must put this packet
+                                in `smol_socket.current_to_send` so it's returned the next time
+                                so we can continue sending it
+                            */
+                            if packet.blob.start < packet.blob.data.len() {
+                                smol_socket.current_to_send = Some(packet);
+                                break;
                             }
+                            //Sent the entire packet, dropping it calls the owner's destructor
+                        }
+                        Err(e) => {
+                            println!("bytes not sent, ERROR {}, putting packet back", e);
+                            smol_socket.current_to_send = Some(packet);
+                            break;
                         }
-                        None => {}
-                    }
-                    //Outside of match because it matches as reference so we cannot move
-                    if put_back {
-                        println!("ATTENTION: putting the packet back");
-                        use std::process;
-                        //TODO: take off exit when things are better reviewed
-                        process::exit(1);
-                        smol_socket.current_to_send = packet;
                     }
-                } else {
-                    //println!("socket cannot send");
-                    //1
                 }
-                if socket.can_recv() {
+                //Graceful close: FIN goes out only after everything queued was sent
+                if (smol_socket.closing || smol_socket.write_shutdown)
+                    && socket.is_open()
+                    && !smol_socket.has_pending_send()
+                {
+                    socket.close();
+                }
+                //In direct mode the data stays in the rx buffer for tcp_recv_slice
+                if socket.can_recv() && !smol_socket.direct_receive {
+                    let remote_endpoint = socket.remote_endpoint();
+                    let closing = smol_socket.closing;
                     socket
                         .recv(|data| {
                             let len = data.len();
+                            //Nobody reads a closed socket, so incoming data is just acknowledged
+                            if closing {
+                                return (len, ());
+                            }
                             {
                                 let mut s = vec![0; len];
                                 s.copy_from_slice(data);
-                                smol_socket.received.lock().unwrap().push_back(s);
+                                smol_socket.received.lock().unwrap().push_back(ReceivedPacket {
+                                    data: s,
+                                    endpoint: Some(remote_endpoint),
+                                });
                             }
-                            let has_data = smol_socket.smol_socket_has_data.as_ref();
-                            let (_, smol_socket_has_data_condition_variable) = &*has_data.clone();
-                            smol_socket_has_data_condition_variable.notify_all();
+                            notify_all(&smol_socket.smol_socket_has_data);
                             /*
                             let has_data = smol_socket.has_data.as_ref().unwrap();
                             let (_, has_data_condition_variable) = &*has_data.clone();
                             has_data_condition_variable.notify_all();
                             */
                             (len, ())
-                        })
-                        .unwrap();
+                        })?;
                 //0
                 } else {
                     //2
                 }
-                0
+                //Everything the peer sent before FIN was read out of the rx buffer
+                if peer_sent_fin(&socket) && !socket.can_recv() {
+                    smol_socket.end_receive(CSmolError::EndOfStream);
+                }
+                //RST, or timed out. LastAck and TimeWait close normally
+                match (smol_socket.last_tcp_state, socket.state()) {
+                    (TcpState::SynSent, TcpState::Closed)
+                    | (TcpState::SynReceived, TcpState::Closed)
+                    | (TcpState::Established, TcpState::Closed)
+                    | (TcpState::FinWait1, TcpState::Closed)
+                    | (TcpState::FinWait2, TcpState::Closed)
+                    | (TcpState::CloseWait, TcpState::Closed)
+                    | (TcpState::Closing, TcpState::Closed) => {
+                        smol_socket.end_receive(CSmolError::ConnectionReset)
+                    }
+                    _ => {}
+                }
+                smol_socket.last_tcp_state = socket.state();
+                Ok(())
+            }
+            SocketType::UDP => {
+                let mut socket = self.sockets.get::<UdpSocket>(smol_socket.socket_handle);
+                //First datagram smoltcp refused, reported once receiving is done
+                let mut dropped = Ok(());
+                //One datagram per packet, until the tx buffer is full
+                while socket.is_open() && socket.can_send() {
+                    let packet = match smol_socket.get_latest_packet() {
+                        Some(packet) => packet,
+                        None => break,
+                    };
+                    //SmolSocket::send makes sure UDP packets always have an endpoint
+                    let endpoint = packet.endpoint.unwrap();
+                    match socket.send_slice(&packet.blob.data.as_slice()[packet.blob.start..], endpoint) {
+                        Ok(_) => {}
+                        //Datagram doesn't fit in what's left of the buffer, try again next spin
+                        Err(smoltcp::Error::Exhausted) => {
+                            smol_socket.current_to_send = Some(packet);
+                            break;
+                        }
+                        //Can't ever be sent (unaddressable, too big...), so it's dropped
+                        Err(e) => {
+                            if dropped.is_ok() {
+                                dropped = Err(SmolError::new(
+                                    CSmolError::Smoltcp,
+                                    format!("datagram not sent: {}", e),
+                                ));
+                            }
+                        }
+                    }
+                }
+                if smol_socket.closing && socket.is_open() && !smol_socket.has_pending_send() {
+                    socket.close();
+                }
+                let mut received_any = false;
+                while socket.can_recv() {
+                    match socket.recv() {
+                        Ok((data, endpoint)) => {
+                            let mut s = vec![0; data.len()];
+                            s.copy_from_slice(data);
+                            smol_socket.received.lock().unwrap().push_back(ReceivedPacket {
+                                data: s,
+                                endpoint: Some(endpoint),
+                            });
+                            received_any = true;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if received_any {
+                    notify_all(&smol_socket.smol_socket_has_data);
+                }
+                dropped
+            }
+            SocketType::ICMP => {
+                let mut socket = self.sockets.get::<IcmpSocket>(smol_socket.socket_handle);
+                let mut received_any = false;
+                while socket.can_recv() {
+                    let (data, source) = match socket.recv() {
+                        Ok(received) => received,
+                        Err(_) => break,
+                    };
+                    match parse_echo_reply(&source, data) {
+                        Some((ident, seq_no, sent_at, payload_len))
+                            if Some(ident) == smol_socket.icmp_ident =>
+                        {
+                            let rtt = cmp::max(Instant::now().total_millis() - sent_at, 0);
+                            smol_socket.echo_replies.push_back(EchoReply {
+                                source: source,
+                                seq_no: seq_no,
+                                rtt: Duration::from_millis(rtt as u64),
+                                payload_len: payload_len,
+                            });
+                            received_any = true;
+                        }
+                        //Not an answer to our requests
+                        _ => {}
+                    }
+                }
+                if received_any {
+                    notify_all(&smol_socket.smol_socket_has_data);
+                }
+                Ok(())
+            }
+            SocketType::RAW_IPV4 | SocketType::RAW_IPV6 => {
+                let mut socket = self.sockets.get::<RawSocket>(smol_socket.socket_handle);
+                //First packet smoltcp refused, reported once receiving is done
+                let mut dropped = Ok(());
+                //One IP packet per packet, until the tx buffer is full
+                while socket.can_send() {
+                    let packet = match smol_socket.get_latest_packet() {
+                        Some(packet) => packet,
+                        None => break,
+                    };
+                    match socket.send_slice(&packet.blob.data.as_slice()[packet.blob.start..]) {
+                        Ok(_) => {}
+                        //Packet doesn't fit in what's left of the buffer, try again next spin
+                        Err(smoltcp::Error::Exhausted) => {
+                            smol_socket.current_to_send = Some(packet);
+                            break;
+                        }
+                        //Can't ever be sent (malformed, too big...), so it's dropped
+                        Err(e) => {
+                            if dropped.is_ok() {
+                                dropped = Err(SmolError::new(
+                                    CSmolError::Smoltcp,
+                                    format!("raw packet not sent: {}", e),
+                                ));
+                            }
+                        }
+                    }
+                }
+                let mut received_any = false;
+                while socket.can_recv() {
+                    match socket.recv() {
+                        Ok(data) => {
+                            smol_socket.received.lock().unwrap().push_back(ReceivedPacket {
+                                data: data.to_vec(),
+                                endpoint: None,
+                            });
+                            received_any = true;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if received_any {
+                    notify_all(&smol_socket.smol_socket_has_data);
+                }
+                dropped
             }
-            SocketType::UDP => panic!("not implemented yet"),
-            //TODO
-            SocketType::ICMP => panic!("not implemented yet"),
-            SocketType::RAW_IPV4 => panic!("not implemented yet"),
-            SocketType::RAW_IPV6 => panic!("not implemented yet"),
         }
     }
 
     //VirtualTun only
     //Send a packet to the stack (Ethernet/IP)
     //not to confuse with TCP/UDP/etc packets
-    pub fn send(&mut self, blob: Blob) -> u8 {
+    pub fn send(&mut self, blob: Blob) -> SmolResult<()> {
         //println!("stack received blob with size {}", blob.data.len());
-        let packets_from_outside = &*self.packets_from_outside.as_ref().unwrap().clone();
-        packets_from_outside.lock().unwrap().push_back(blob);
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
+        let mtu = self.mtu()?;
+        //The device would have to truncate it
+        if blob.data.len() > mtu {
+            return Err(SmolError::new(
+                CSmolError::InvalidArgument,
+                format!(
+                    "packet of {} bytes is bigger than the MTU ({})",
+                    blob.data.len(),
+                    mtu
+                ),
+            ));
+        }
+        let packets_from_outside = &*self
+            .packets_from_outside
+            .as_ref()
+            .ok_or_else(SmolError::virtual_tun_only)?
+            .clone();
+        {
+            let mut packets_from_outside = packets_from_outside.lock().unwrap();
+            if let Some(queue_limits) = self.queue_limits.as_ref() {
+                if queue_limits.outside_full(packets_from_outside.len()) {
+                    //Either way the blob is dropped here, destructing its owner
+                    return match queue_limits.drop_policy {
+                        DropPolicy::TailDrop => {
+                            queue_limits
+                                .dropped
+                                .from_outside
+                                .fetch_add(1, Ordering::Relaxed);
+                            Ok(())
+                        }
+                        DropPolicy::Error => Err(SmolError::new(
+                            CSmolError::QueueFull,
+                            "queue of packets sent to the stack is full",
+                        )),
+                    };
+                }
+            }
+            packets_from_outside.push_back(blob);
+        }
         //Unlock the poller thread because new data is available
-        has_data_condition_variable.notify_all();
-        0
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
     }
 
     /*
@@ -642,11 +2329,15 @@ where
         &mut self,
         cbuffer: *mut CBuffer,
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    ) -> u8 {
+    ) -> SmolResult<()> {
         let s;
 
         //let has_data = &*self.has_data.as_ref().unwrap().clone();
-        let packets_from_inside = &*self.packets_from_inside.as_ref().unwrap().clone();
+        let packets_from_inside = &*self
+            .packets_from_inside
+            .as_ref()
+            .ok_or_else(SmolError::virtual_tun_only)?
+            .clone();
         {
             //Create a scope so we hold the queue for the least ammount needed
             //TODO: do I really need to create a scope?
@@ -664,36 +2355,113 @@ where
                 //Sends the pointer back to C++, which has the responsibility
                 //to delete it
                 //TODO: remove +1
-
-                unsafe {
-                    *cbuffer = CBuffer {
+                write_out(
+                    cbuffer,
+                    CBuffer {
                         data: p,
                         len: s.len(),
-                    };
-                }
-                let (mutex, has_data_condition_variable) =
-                    &*self.has_data.as_ref().unwrap().clone();
+                    },
+                )?;
                 //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                //0 means everything went well
-                0
+                notify_all(self.has_data.as_ref().unwrap());
+                Ok(())
             }
-            None => 1,
+            None => Err(SmolError::new(CSmolError::NoData, "nothing to receive")),
         }
     }
 
     /*
-        Returns 0 in case of sucess
-        Returns 1 if there's no packet to receive
+        VirtualTun only. Same as SmolSocket::receive_into, for
+        packets (or frames) coming out of the stack
+    */
+    pub fn receive_into(&mut self, buffer: *mut u8, len: usize, written: *mut usize) -> SmolResult<()> {
+        check_out_buffer(buffer, len, written)?;
+        let packets_from_inside = &*self
+            .packets_from_inside
+            .as_ref()
+            .ok_or_else(SmolError::virtual_tun_only)?
+            .clone();
+        let s;
+        {
+            let mut packets_from_inside = packets_from_inside.lock().unwrap();
+            let needed = match packets_from_inside.front() {
+                Some(s) => s.len(),
+                None => return Err(SmolError::new(CSmolError::NoData, "nothing to receive")),
+            };
+            check_fits(needed, len, written)?;
+            s = packets_from_inside.pop_front().unwrap();
+        }
+        unsafe { ptr::copy_nonoverlapping(s.as_ptr(), buffer, s.len()) };
+        write_out(written, s.len())?;
+        //Unlock the poller thread because there's room in the queue now
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Reads up to len bytes of the TCP stream into buffer, straight
+        from the smoltcp rx buffer, and writes how many to written.
+        The first call switches the socket to direct mode for good:
+        data is no longer queued for receive/receive_wait. Data queued
+        before the switch is returned first, so nothing is reordered
+    */
+    pub fn tcp_recv_slice(
+        &mut self,
+        smol_socket_handle: usize,
+        buffer: *mut u8,
+        len: usize,
+        written: *mut usize,
+    ) -> SmolResult<()> {
+        check_out_buffer(buffer, len, written)?;
+        if len == 0 {
+            return Err(SmolError::new(CSmolError::InvalidArgument, "empty buffer"));
+        }
+        let smol_socket = typed_smol_socket(&mut self.smol_sockets, smol_socket_handle, SocketType::TCP)?;
+        smol_socket.direct_receive = true;
+        let buffer = unsafe { slice::from_raw_parts_mut(buffer, len) };
+        {
+            let mut received = smol_socket.received.lock().unwrap();
+            if let Some(s) = received.front_mut() {
+                let n = cmp::min(len, s.data.len());
+                buffer[..n].copy_from_slice(&s.data[..n]);
+                if n == s.data.len() {
+                    received.pop_front();
+                } else {
+                    s.data.drain(..n);
+                }
+                return write_out(written, n);
+            }
+        }
+        let mut socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+        if !socket.can_recv() {
+            let ended = match *smol_socket.ended.lock().unwrap() {
+                None if peer_sent_fin(&socket) => Some(CSmolError::EndOfStream),
+                ended => ended,
+            };
+            return Err(nothing_to_receive(ended));
+        }
+        let n = socket.recv_slice(buffer)?;
+        write_out(written, n)?;
+        //Unlock the poller thread so the window update goes out
+        notify_all(self.has_data.as_ref().unwrap());
+        Ok(())
+    }
+
+    /*
+        Returns NoData if there's no packet to receive
     */
     pub fn receive_instantly(
         &mut self,
         cbuffer: *mut CBuffer,
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    ) -> u8 {
+    ) -> SmolResult<()> {
         let s;
         //We ignore the condvar because we want to return immediately
-        let packets_from_inside = &*self.packets_from_inside.as_ref().unwrap().clone();
+        let packets_from_inside = &*self
+            .packets_from_inside
+            .as_ref()
+            .ok_or_else(SmolError::virtual_tun_only)?
+            .clone();
         {
             //Create a scope so we hold the queue for the least ammount needed
             //TODO: do I really need to create a scope?
@@ -708,34 +2476,224 @@ where
                 unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
                 //Sends the pointer back to C++, which has the responsibility
                 //to delete it
-                unsafe {
-                    *cbuffer = CBuffer {
+                write_out(
+                    cbuffer,
+                    CBuffer {
                         data: p,
                         len: s.len(),
-                    };
-                }
-                let (mutex, has_data_condition_variable) =
-                    &*self.has_data.as_ref().unwrap().clone();
+                    },
+                )?;
                 //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                0
+                notify_all(self.has_data.as_ref().unwrap());
+                Ok(())
             }
-            None => 1,
+            None => Err(SmolError::new(CSmolError::NoData, "nothing to receive")),
         }
     }
 
     /*
-        Waits until either data was sent or received, that is,
-        either packets_from_outside or packets_from_inside
-        have changed
+        How long until the interface needs to be polled again,
+        None if nothing is scheduled
     */
-    pub fn phy_wait(&mut self) {
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-        has_data_condition_variable.wait(mutex.lock().unwrap());
+    pub fn poll_delay(&mut self) -> SmolResult<Option<Duration>> {
+        self.poll_delay_at(Instant::now())
+    }
+
+    //How long after timestamp the interface has to be polled again
+    pub fn poll_delay_at(&mut self, timestamp: Instant) -> SmolResult<Option<Duration>> {
+        let interface = self.interface.as_mut().ok_or_else(|| {
+            SmolError::new(CSmolError::NotFinalized, "stack is not finalized yet")
+        })?;
+        let mut delay = interface.poll_delay(&self.sockets, timestamp);
+        //The DHCP client has its own timers (retransmits, renewal)
+        if let Some(dhcp) = self.dhcp.as_ref() {
+            let dhcp_delay = dhcp.client.next_poll(timestamp);
+            delay = Some(delay.map_or(dhcp_delay, |delay| cmp::min(delay, dhcp_delay)));
+        }
+        //Pending DNS queries are retried or given up on
+        for query in self.dns_queries.iter() {
+            let millis = cmp::max(query.next_send.total_millis() - timestamp.total_millis(), 0);
+            let dns_delay = smoltcp::time::Duration::from_millis(millis as u64);
+            delay = Some(delay.map_or(dns_delay, |delay| cmp::min(delay, dns_delay)));
+        }
+        Ok(delay.map(|delay| Duration::from_millis(delay.total_millis())))
+    }
+
+    //The device's MTU, whether the stack was finalized or not
+    pub fn mtu(&self) -> SmolResult<usize> {
+        match (&self.device, &self.interface) {
+            (Some(device), _) => Ok(device.capabilities().max_transmission_unit),
+            (None, Some(interface)) => Ok(interface.device().capabilities().max_transmission_unit),
+            //device is only taken by finalize, which creates interface
+            (None, None) => Err(SmolError::new(CSmolError::Device, "stack has no device")),
+        }
+    }
+
+    //VirtualTun only
+    pub fn drop_counters(&self) -> SmolResult<Arc<DropCounters>> {
+        self.queue_limits
+            .as_ref()
+            .map(|queue_limits| queue_limits.dropped.clone())
+            .ok_or_else(SmolError::virtual_tun_only)
+    }
+
+    pub fn has_data(&self) -> Option<Arc<(Mutex<u64>, Condvar)>> {
+        self.has_data.clone()
+    }
+
+    pub fn polled_notifications(&self) -> u64 {
+        self.polled_notifications
+    }
+
+    //VirtualTun only: packets sent to the stack that poll didn't take yet
+    pub fn has_packets_from_outside(&self) -> bool {
+        self.packets_from_outside
+            .as_ref()
+            .map_or(false, |packets_from_outside| !packets_from_outside.lock().unwrap().is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //What icmp_send_echo puts in an echo request, and a peer echoes back
+    fn echo_payload(sent_at: i64, data: &[u8]) -> Vec<u8> {
+        let mut payload = sent_at.to_be_bytes().to_vec();
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    fn icmpv4(repr: Icmpv4Repr) -> Vec<u8> {
+        let mut buffer = vec![0; repr.buffer_len()];
+        repr.emit(&mut Icmpv4Packet::new_unchecked(&mut buffer), &ChecksumCapabilities::default());
+        buffer
+    }
+
+    fn icmpv6(source: &IpAddress, repr: Icmpv6Repr) -> Vec<u8> {
+        let mut buffer = vec![0; repr.buffer_len()];
+        repr.emit(
+            source,
+            &IpAddress::v6(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+            &mut Icmpv6Packet::new_unchecked(&mut buffer),
+            &ChecksumCapabilities::default(),
+        );
+        buffer
+    }
+
+    #[test]
+    fn sent_at_needs_eight_bytes() {
+        assert_eq!(sent_at(&[]), None);
+        assert_eq!(sent_at(&[0; 7]), None);
+    }
+
+    #[test]
+    fn sent_at_reads_big_endian_millis() {
+        assert_eq!(sent_at(&echo_payload(1_234_567, b"ping")), Some(1_234_567));
+        assert_eq!(sent_at(&echo_payload(-1, b"")), Some(-1));
+    }
+
+    #[test]
+    fn parses_ipv4_echo_reply() {
+        let payload = echo_payload(42, b"hello");
+        let packet = icmpv4(Icmpv4Repr::EchoReply {
+            ident: 0x1234,
+            seq_no: 7,
+            data: &payload,
+        });
+        let source = IpAddress::v4(192, 168, 69, 1);
+        assert_eq!(parse_echo_reply(&source, &packet), Some((0x1234, 7, 42, 5)));
+    }
+
+    #[test]
+    fn parses_ipv6_echo_reply() {
+        let payload = echo_payload(42, b"");
+        let source = IpAddress::v6(0xfe80, 0, 0, 0, 0, 0, 0, 0x100);
+        let packet = icmpv6(
+            &source,
+            Icmpv6Repr::EchoReply {
+                ident: 1,
+                seq_no: 2,
+                data: &payload,
+            },
+        );
+        assert_eq!(parse_echo_reply(&source, &packet), Some((1, 2, 42, 0)));
+    }
+
+    #[test]
+    fn ignores_echo_requests() {
+        let payload = echo_payload(42, b"hello");
+        let packet = icmpv4(Icmpv4Repr::EchoRequest {
+            ident: 1,
+            seq_no: 1,
+            data: &payload,
+        });
+        assert_eq!(parse_echo_reply(&IpAddress::v4(10, 0, 0, 1), &packet), None);
+    }
+
+    #[test]
+    fn ignores_reply_without_send_time() {
+        let packet = icmpv4(Icmpv4Repr::EchoReply {
+            ident: 1,
+            seq_no: 1,
+            data: &[1, 2, 3],
+        });
+        assert_eq!(parse_echo_reply(&IpAddress::v4(10, 0, 0, 1), &packet), None);
+    }
+
+    #[test]
+    fn ignores_truncated_packet() {
+        let payload = echo_payload(42, b"hello");
+        let packet = icmpv4(Icmpv4Repr::EchoReply {
+            ident: 1,
+            seq_no: 1,
+            data: &payload,
+        });
+        assert_eq!(parse_echo_reply(&IpAddress::v4(10, 0, 0, 1), &packet[..6]), None);
+        assert_eq!(parse_echo_reply(&IpAddress::Unspecified, &packet), None);
+    }
+
+    #[test]
+    fn rtt_is_zero_until_acknowledged() {
+        let mut rtt = RttEstimator::new();
+        rtt.sent(100, true, Instant::from_millis(1000));
+        rtt.acked(40, Instant::from_millis(1050));
+        assert_eq!(rtt.millis(), 0);
+        rtt.acked(0, Instant::from_millis(1080));
+        assert_eq!(rtt.millis(), 80);
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut rtt = RttEstimator::new();
+        rtt.sent(10, true, Instant::from_millis(0));
+        rtt.acked(0, Instant::from_millis(80));
+        rtt.sent(10, true, Instant::from_millis(100));
+        rtt.acked(0, Instant::from_millis(260));
+        //7/8 of 80 plus 1/8 of 160
+        assert_eq!(rtt.millis(), 90);
+    }
+
+    #[test]
+    fn rtt_times_one_segment_sent_on_an_idle_buffer() {
+        let mut rtt = RttEstimator::new();
+        //Waits behind queued data, not timed
+        rtt.sent(10, false, Instant::from_millis(0));
+        rtt.sent(10, true, Instant::from_millis(100));
+        //Already timing the first, not timed either
+        rtt.sent(10, true, Instant::from_millis(150));
+        rtt.acked(10, Instant::from_millis(200));
+        assert_eq!(rtt.millis(), 100);
+        rtt.acked(0, Instant::from_millis(300));
+        assert_eq!(rtt.millis(), 100);
     }
 
-    pub fn phy_wait_timeout(&mut self, duration: Duration) {
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-        has_data_condition_variable.wait_timeout(mutex.lock().unwrap(), duration);
+    #[test]
+    fn rtt_drops_the_sample_of_a_lost_connection() {
+        let mut rtt = RttEstimator::new();
+        rtt.sent(10, true, Instant::from_millis(0));
+        rtt.stop();
+        rtt.acked(0, Instant::from_millis(50));
+        assert_eq!(rtt.millis(), 0);
     }
 }
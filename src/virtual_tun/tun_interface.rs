@@ -13,7 +13,7 @@ use time::Instant;
 #[derive(Debug)]
 pub struct TunInterface {
     lower:  Rc<RefCell<sys::TunInterfaceDesc>>,
-    has_data: Arc<(Mutex<()>, Condvar)>,
+    has_data: Arc<(Mutex<u64>, Condvar)>,
     mtu:    usize
 }
 
@@ -9,6 +9,7 @@ use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::io;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::vec::Vec;
 use std::time::Duration;
@@ -18,33 +19,86 @@ use std::slice;
 
 static ERR_WOULD_BLOCK: u32 = 1;
 
+//What happens to a packet that arrives at a full queue
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DropPolicy {
+    //The packet is dropped and counted
+    TailDrop,
+    /*
+        The packet is refused: smol_stack_virtual_tun_send returns an
+        error, and smoltcp keeps the packet to transmit it later
+    */
+    Error,
+}
+
+//Packets dropped so far because a queue was full
+#[derive(Default)]
+pub struct DropCounters {
+    pub from_inside: AtomicU64,
+    pub from_outside: AtomicU64,
+}
+
+/*
+    Capacities (in packets) of packets_from_inside, which holds what
+    smoltcp transmits until C++ receives it, and packets_from_outside,
+    which holds what C++ sends until smoltcp receives it. 0 means unbounded
+*/
 #[derive(Clone)]
-pub struct VirtualTunInterface {
-    mtu: usize,
-    has_data: Arc<(Mutex<()>, Condvar)>,
-    packets_from_inside: Arc<Mutex<VecDeque<Vec<u8>>>>,
-    packets_from_outside: Arc<Mutex<VecDeque<Blob>>>,
+pub struct QueueLimits {
+    pub inside_capacity: usize,
+    pub outside_capacity: usize,
+    pub drop_policy: DropPolicy,
+    pub dropped: Arc<DropCounters>,
 }
 
-fn copy_slice(dst: &mut [u8], src: &[u8]) -> usize {
-    let mut c = 0;
-    for (d, s) in dst.iter_mut().zip(src.iter()) {
-        *d = *s;
-        c += 1;
+impl QueueLimits {
+    pub fn unbounded() -> QueueLimits {
+        QueueLimits {
+            inside_capacity: 0,
+            outside_capacity: 0,
+            drop_policy: DropPolicy::TailDrop,
+            dropped: Arc::new(DropCounters::default()),
+        }
+    }
+
+    pub fn inside_full(&self, len: usize) -> bool {
+        self.inside_capacity != 0 && len >= self.inside_capacity
+    }
+
+    pub fn outside_full(&self, len: usize) -> bool {
+        self.outside_capacity != 0 && len >= self.outside_capacity
     }
-    c 
+}
+
+#[derive(Clone)]
+pub struct VirtualTunInterface {
+    //MTU, checksum behaviour and max burst size, as given to new
+    capabilities: DeviceCapabilities,
+    queue_limits: QueueLimits,
+    has_data: Arc<(Mutex<u64>, Condvar)>,
+    packets_from_inside: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    packets_from_outside: Arc<Mutex<VecDeque<Blob>>>,
 }
 
 impl<'a> VirtualTunInterface {
+    /*
+        capabilities.max_transmission_unit must be set, as it's
+        the size of the buffer each packet is received into
+    */
     pub fn new(
         _name: &str,
+        capabilities: DeviceCapabilities,
+        queue_limits: QueueLimits,
         packets_from_inside: Arc<Mutex<VecDeque<Vec<u8>>>>,
         packets_from_outside: Arc<Mutex<VecDeque<Blob>>>,
-        has_data: Arc<(Mutex<()>, Condvar)>
+        has_data: Arc<(Mutex<u64>, Condvar)>
     ) -> Result<VirtualTunInterface> {
-        let mtu = 1500; //??
+        if capabilities.max_transmission_unit == 0 {
+            return Err(Error::Illegal);
+        }
         Ok(VirtualTunInterface {
-            mtu: mtu,
+            capabilities: capabilities,
+            queue_limits: queue_limits,
             has_data: has_data,
             packets_from_outside: packets_from_outside,
             packets_from_inside: packets_from_inside,
@@ -53,16 +107,31 @@ impl<'a> VirtualTunInterface {
     //TODO: this cant block, I guess?? Or it can..
     fn recv(&mut self, buffer: &mut [u8]) -> core::result::Result<usize, u32> {
         let packets_from_outside = &*self.packets_from_outside.clone();
-        let p;
-        {
+        let mut p;
+        loop {
             p = packets_from_outside.lock().unwrap().pop_front();
+            match &p {
+                /*
+                    SmolStack::send already refuses packets bigger than the MTU,
+                    this only protects from truncating one that got here anyway
+                */
+                Some(packet) if packet.data.len() > buffer.len() => {
+                    println!(
+                        "dropping packet of {} bytes, bigger than the MTU ({})",
+                        packet.data.len(),
+                        buffer.len()
+                    );
+                }
+                _ => break,
+            }
         }
         match p {
             Some(packet) => {
-                copy_slice(buffer, packet.data.as_slice());
+                let len = packet.data.len();
+                buffer[..len].copy_from_slice(packet.data.as_slice());
                 let (mutex, has_data_condition_variable) = &*self.has_data.clone();
                 has_data_condition_variable.notify_one();
-                Ok(packet.data.len())
+                Ok(len)
             }
             /*
                 Simply returns ERR_WOULD_BLOCK. Device::receive(&mut self) is prepared
@@ -78,13 +147,11 @@ impl<'d> Device<'d> for VirtualTunInterface {
     type TxToken = TxToken;
 
     fn capabilities(&self) -> DeviceCapabilities {
-        let mut d = DeviceCapabilities::default();
-        d.max_transmission_unit = self.mtu;
-        d
+        self.capabilities.clone()
     }
 
     fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let mut buffer = vec![0; self.mtu];
+        let mut buffer = vec![0; self.capabilities.max_transmission_unit];
         match self.recv(&mut buffer[..]) {
             Ok(size) => {
                 buffer.resize(size, 0);
@@ -114,6 +181,63 @@ impl<'d> Device<'d> for VirtualTunInterface {
     }
 }
 
+/*
+    VirtualTunInterface for Ethernet frames instead of IP packets,
+    the in-memory counterpart of a TAP device. Everything but the
+    medium is the same, so it just wraps one
+*/
+#[derive(Clone)]
+pub struct VirtualTapInterface {
+    lower: VirtualTunInterface,
+}
+
+impl VirtualTapInterface {
+    /*
+        capabilities.max_transmission_unit is the size of a
+        whole frame, Ethernet header included
+    */
+    pub fn new(
+        name: &str,
+        capabilities: DeviceCapabilities,
+        queue_limits: QueueLimits,
+        packets_from_inside: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        packets_from_outside: Arc<Mutex<VecDeque<Blob>>>,
+        has_data: Arc<(Mutex<u64>, Condvar)>
+    ) -> Result<VirtualTapInterface> {
+        Ok(VirtualTapInterface {
+            lower: VirtualTunInterface::new(
+                name,
+                capabilities,
+                queue_limits,
+                packets_from_inside,
+                packets_from_outside,
+                has_data,
+            )?,
+        })
+    }
+}
+
+impl<'d> Device<'d> for VirtualTapInterface {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.lower.capabilities()
+    }
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        self.lower.receive()
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken> {
+        self.lower.transmit()
+    }
+
+    fn medium(&self) -> Medium {
+        Medium::Ethernet
+    }
+}
+
 #[doc(hidden)]
 pub struct RxToken {
     lower: Rc<RefCell<VirtualTunInterface>>,
@@ -144,12 +268,26 @@ impl<'a> phy::TxToken for TxToken {
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
         let mut lower = self.lower.as_ref().borrow_mut();
+        let packets_from_inside = &*lower.packets_from_inside.clone();
+        let full = lower
+            .queue_limits
+            .inside_full(packets_from_inside.lock().unwrap().len());
+        if full && lower.queue_limits.drop_policy == DropPolicy::Error {
+            //smoltcp keeps the data and tries again on a later poll
+            return Err(Error::Exhausted);
+        }
         let mut buffer = vec![0; len];
         let result = f(&mut buffer);
-        
-        let packets_from_inside = &*lower.packets_from_inside.clone();
-        {
-            packets_from_inside.lock().unwrap().push_back(buffer);
+        if result.is_ok() {
+            if full {
+                lower
+                    .queue_limits
+                    .dropped
+                    .from_inside
+                    .fetch_add(1, Ordering::Relaxed);
+            } else {
+                packets_from_inside.lock().unwrap().push_back(buffer);
+            }
         }
         
         //TODO: I think this is not necessary?
@@ -158,3 +296,41 @@ impl<'a> phy::TxToken for TxToken {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(inside_capacity: usize, outside_capacity: usize) -> QueueLimits {
+        QueueLimits {
+            inside_capacity: inside_capacity,
+            outside_capacity: outside_capacity,
+            ..QueueLimits::unbounded()
+        }
+    }
+
+    #[test]
+    fn unbounded_queues_are_never_full() {
+        let queue_limits = QueueLimits::unbounded();
+        assert!(!queue_limits.inside_full(0));
+        assert!(!queue_limits.inside_full(usize::max_value()));
+        assert!(!queue_limits.outside_full(usize::max_value()));
+    }
+
+    #[test]
+    fn queues_are_full_at_capacity() {
+        let queue_limits = limits(2, 3);
+        assert!(!queue_limits.inside_full(1));
+        assert!(queue_limits.inside_full(2));
+        assert!(queue_limits.inside_full(3));
+        assert!(!queue_limits.outside_full(2));
+        assert!(queue_limits.outside_full(3));
+    }
+
+    #[test]
+    fn capacities_are_independent() {
+        let queue_limits = limits(1, 0);
+        assert!(queue_limits.inside_full(1));
+        assert!(!queue_limits.outside_full(1000));
+    }
+}